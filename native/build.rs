@@ -1,27 +1,74 @@
 // native/build.rs
-use std::{env, path::Path};
+use std::{env, path::Path, process::Command};
 
 fn main() {
     // ────────────────────────────────────────────────────────────────
     // 1. Tell the linker where it can *find* libappleai.dylib *now*
     //    (need an absolute path because Cargo builds in a tmp dir)
     // ────────────────────────────────────────────────────────────────
+    //    APPLE_AI_LIB_DIR is searched first, for layouts that keep the
+    //    dylib somewhere other than ../build
+    println!("cargo:rerun-if-env-changed=APPLE_AI_LIB_DIR");
+    if let Some(lib_dir) = env::var_os("APPLE_AI_LIB_DIR") {
+        println!(
+            "cargo:rustc-link-search=native={}",
+            Path::new(&lib_dir).display()
+        );
+    }
     let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("no CARGO_MANIFEST_DIR");
     let build_dir = Path::new(&manifest_dir).join("../build");
     println!("cargo:rustc-link-search=native={}", build_dir.display());
 
     // Link against libappleai.dylib  (lib… prefix + .dylib suffix are implied)
-    println!("cargo:rustc-link-lib=dylib=appleai");
+    // APPLE_AI_LIB_NAME overrides the name for renamed or versioned builds,
+    // e.g. `appleai_v2` for libappleai_v2.dylib
+    // The `mock` feature and non-macOS targets compile in Rust stubs instead,
+    // so there is nothing to link
+    println!("cargo:rerun-if-env-changed=APPLE_AI_LIB_NAME");
+    let lib_name = env::var("APPLE_AI_LIB_NAME").unwrap_or_else(|_| "appleai".to_string());
+    let target_macos = env::var("CARGO_CFG_TARGET_OS").is_ok_and(|os| os == "macos");
+    // `static-link` takes libappleai.a instead, so no dylib ships with the .node
+    let static_link = env::var_os("CARGO_FEATURE_STATIC_LINK").is_some();
+    if target_macos && env::var_os("CARGO_FEATURE_MOCK").is_none() {
+        if static_link {
+            println!("cargo:rustc-link-lib=static={lib_name}");
+            // The archive brings no dependencies of its own: the Swift
+            // runtime is part of macOS, and so are the frameworks it
+            // imports. Since Big Sur the runtime dylibs only live in the
+            // shared cache, so link against the SDK's stubs for them
+            println!("cargo:rerun-if-env-changed=SDKROOT");
+            let sdk = Command::new("xcrun")
+                .arg("--show-sdk-path")
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .expect("static-link needs Xcode or the Command Line Tools (`xcrun --show-sdk-path` failed)");
+            let sdk = String::from_utf8_lossy(&sdk.stdout);
+            println!(
+                "cargo:rustc-link-search=native={}",
+                Path::new(sdk.trim()).join("usr/lib/swift").display()
+            );
+            for framework in ["Foundation", "FoundationModels", "NaturalLanguage"] {
+                println!("cargo:rustc-link-lib=framework={framework}");
+            }
+        } else {
+            println!("cargo:rustc-link-lib=dylib={lib_name}");
+        }
+    }
 
     // ────────────────────────────────────────────────────────────────
     // 2. macOS-specific tweaks so the finished .node can *load*
     //    the dylib at run-time, and let Node/Bun resolve N-API symbols
     // ────────────────────────────────────────────────────────────────
-    if cfg!(target_os = "macos") {
-        // Make the loader search in the directory that contains the .node
-        println!("cargo:rustc-link-arg=-Wl,-rpath,@loader_path");
+    if target_macos {
+        // Make the loader search in the directory that contains the .node;
+        // a statically linked build has nothing to load from there
+        if !static_link {
+            println!("cargo:rustc-link-arg=-Wl,-rpath,@loader_path");
+        }
 
-        // Let unresolved symbols be patched in later by Node/Bun
+        // Let unresolved symbols be patched in later by Node/Bun (needed
+        // however the Swift code is linked)
         println!("cargo:rustc-link-arg=-undefined");
         println!("cargo:rustc-link-arg=dynamic_lookup");
     }
@@ -3,38 +3,117 @@ use napi::bindgen_prelude::*;
 use napi::threadsafe_function::{
     ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
 };
-use napi::JsString;
+use napi::{JsObject, JsString, JsUnknown, NapiRaw};
 use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr, CString};
-use std::sync::{Mutex, OnceLock};
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
 // -------- FFI declarations to Swift dylib --------
-#[link(name = "appleai")]
+// Linked by build.rs, which lets APPLE_AI_LIB_NAME rename the library
+#[cfg(all(target_os = "macos", not(feature = "mock")))]
 extern "C" {
     fn apple_ai_init() -> bool;
+    fn apple_ai_shutdown();
+    // Strings returned by the library must be released through it
+    fn apple_ai_free_string(ptr: *mut c_char);
     fn apple_ai_check_availability() -> c_int;
     fn apple_ai_get_availability_reason() -> *mut c_char;
+    fn apple_ai_get_availability_reason_code() -> c_int;
+    fn apple_ai_prewarm() -> bool;
 
     fn apple_ai_get_supported_languages_count() -> c_int;
     fn apple_ai_get_supported_language(index: c_int) -> *mut c_char;
+    fn apple_ai_get_supported_language_tag(index: c_int) -> *mut c_char;
 
-    fn apple_ai_generate_response(
+    fn apple_ai_detect_language(text: *const c_char, max_candidates: c_int) -> *mut c_char;
+    fn apple_ai_generate_embedding(text: *const c_char) -> *mut c_char;
+
+    fn apple_ai_get_model_name() -> *mut c_char;
+    fn apple_ai_get_model_version() -> *mut c_char;
+    fn apple_ai_last_generation_on_device() -> bool;
+    fn apple_ai_supports_feature(feature: c_int) -> bool;
+    fn apple_ai_get_model_memory_usage() -> i64;
+
+    fn apple_ai_get_context_window() -> c_int;
+    fn apple_ai_count_tokens(text: *const c_char) -> c_int;
+    fn apple_ai_count_tokens_for_messages(messages_json: *const c_char) -> c_int;
+
+    fn apple_ai_cancel_request(handle: u64);
+
+    // `_v2` entry points take their settings as an options JSON string
+    fn apple_ai_generate_with_instructions(
+        instructions: *const c_char,
         prompt: *const c_char,
-        temperature: c_double,
-        max_tokens: c_int,
+        options_json: *const c_char,
+        handle: u64,
     ) -> *mut c_char;
 
-    fn apple_ai_generate_response_with_history(
+    fn apple_ai_generate_response_with_history_v2(
         messages_json: *const c_char,
-        temperature: c_double,
-        max_tokens: c_int,
+        options_json: *const c_char,
+        handle: u64,
     ) -> *mut c_char;
 
-    fn apple_ai_generate_response_stream(
+    fn apple_ai_generate_response_stream_v2(
         prompt: *const c_char,
-        temperature: c_double,
-        max_tokens: c_int,
-        on_chunk: extern "C" fn(*const c_char),
+        options_json: *const c_char,
+        stream_id: u32,
+        on_chunk: extern "C" fn(u32, *const c_char),
+    );
+
+    fn apple_ai_generate_response_with_history_stream(
+        messages_json: *const c_char,
+        options_json: *const c_char,
+        stream_id: u32,
+        on_chunk: extern "C" fn(u32, *const c_char),
+    );
+
+    fn apple_ai_cancel_stream(stream_id: u32);
+
+    fn apple_ai_session_create(instructions: *const c_char) -> u32;
+    fn apple_ai_session_respond(
+        session_id: u32,
+        prompt: *const c_char,
+        options_json: *const c_char,
+        handle: u64,
+    ) -> *mut c_char;
+    fn apple_ai_session_respond_stream(
+        session_id: u32,
+        prompt: *const c_char,
+        options_json: *const c_char,
+        stream_id: u32,
+        on_chunk: extern "C" fn(u32, *const c_char),
+    );
+    fn apple_ai_session_continue(
+        session_id: u32,
+        options_json: *const c_char,
+        handle: u64,
+    ) -> *mut c_char;
+    fn apple_ai_session_reset(session_id: u32) -> bool;
+    fn apple_ai_session_export(session_id: u32) -> *mut c_char;
+    fn apple_ai_session_load_history(session_id: u32, messages_json: *const c_char) -> bool;
+    fn apple_ai_session_free(session_id: u32);
+
+    fn apple_ai_generate_structured(
+        prompt: *const c_char,
+        schema_json: *const c_char,
+        options_json: *const c_char,
+        handle: u64,
+    ) -> *mut c_char;
+
+    fn apple_ai_generate_structured_stream(
+        prompt: *const c_char,
+        schema_json: *const c_char,
+        options_json: *const c_char,
+        stream_id: u32,
+        on_chunk: extern "C" fn(u32, *const c_char),
     );
 
     fn apple_ai_generate_response_structured(
@@ -43,62 +122,517 @@ extern "C" {
         temperature: c_double,
         max_tokens: c_int,
     ) -> *mut c_char;
+
+    fn apple_ai_generate_with_tools(
+        prompt: *const c_char,
+        tools_json: *const c_char,
+        options_json: *const c_char,
+        handle: u64,
+    ) -> *mut c_char;
+    fn apple_ai_generate_with_tools_stream(
+        prompt: *const c_char,
+        tools_json: *const c_char,
+        options_json: *const c_char,
+        stream_id: u32,
+        on_chunk: extern "C" fn(u32, *const c_char),
+    );
+    fn apple_ai_submit_tool_result(stream_id: u32, result: *const c_char) -> bool;
+
+    fn apple_ai_generate_with_image(
+        prompt: *const c_char,
+        image_data: *const u8,
+        image_len: usize,
+        format: *const c_char,
+        options_json: *const c_char,
+        handle: u64,
+    ) -> *mut c_char;
+
+    fn apple_ai_classify_prompt(prompt: *const c_char, handle: u64) -> *mut c_char;
 }
 
+// Built with `--features mock`, the same functions come from a Rust stub
+#[cfg(feature = "mock")]
+mod mock;
+#[cfg(feature = "mock")]
+use mock::*;
+
+// Elsewhere the Swift library doesn't exist; the stubs report the platform as unsupported
+#[cfg(all(not(target_os = "macos"), not(feature = "mock")))]
+mod unsupported;
+#[cfg(all(not(target_os = "macos"), not(feature = "mock")))]
+use unsupported::*;
+
 // --------------------------------------------------
 
-/// Lazily ensure the Swift library is initialized exactly once.
-fn ensure_initialized() {
-    static ONCE: std::sync::Once = std::sync::Once::new();
-    ONCE.call_once(|| unsafe {
-        if !apple_ai_init() {
-            panic!("Failed to initialize Apple AI native library");
+/// Lock `mutex`, recovering it if a panic poisoned it. The state behind each
+/// lock here stays usable after a half-finished update, whereas failing
+/// every later call would turn one panic into a panic across the N-API
+/// boundary on each of them. This only matters in unwinding builds.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// `condvar.wait(guard)`, recovering from poisoning like `lock`.
+fn wait<'a, T>(condvar: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    condvar.wait(guard).unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Outcome of `apple_ai_init`; `None` until first use and after `shutdown`.
+static INIT_RESULT: Mutex<Option<std::result::Result<(), String>>> = Mutex::new(None);
+
+#[cfg(target_os = "macos")]
+const INIT_ERROR: &str = "Failed to initialize Apple AI native library";
+#[cfg(not(target_os = "macos"))]
+const INIT_ERROR: &str = "Apple AI is not supported on this platform (requires macOS 26+)";
+
+/// Lazily ensure the Swift library is initialized exactly once (until the
+/// next `shutdown`).
+///
+/// The outcome is cached, so every call after a failed init returns the same
+/// error instead of retrying (or panicking across the N-API boundary).
+fn ensure_initialized() -> napi::Result<()> {
+    let mut init = lock(&INIT_RESULT);
+    init.get_or_insert_with(|| unsafe {
+        if apple_ai_init() {
+            log_event("info", || "Native library initialized".to_string());
+            Ok(())
+        } else {
+            let error = init_error();
+            log_event("error", || error.clone());
+            Err(error)
         }
-    });
+    })
+    .clone()
+    .map_err(napi::Error::from_reason)
+}
+
+/// `INIT_ERROR` with the availability reason, which usually says what to
+/// fix (e.g. enabling Apple Intelligence), when there is one beyond the
+/// platform being unsupported.
+fn init_error() -> String {
+    let reason = availability_reason();
+    if reason.is_empty() || availability_reason_code() == AvailabilityReason::UnsupportedPlatform {
+        INIT_ERROR.to_string()
+    } else {
+        format!("{INIT_ERROR}: {reason}")
+    }
+}
+
+/// Explicitly initialize the native library.
+///
+/// Calling this is optional – every entry point initializes lazily – but it
+/// lets JS detect a missing/unsupported backend up front with a `try/catch`.
+#[napi]
+pub fn initialize() -> napi::Result<()> {
+    ensure_initialized()
+}
+
+/// Release the native resources (prewarmed model, sessions) so the memory
+/// can be reclaimed; the next call initializes the library again.
+///
+/// Active streams are cancelled, as by `cancel_stream`. Pending generations
+/// reject with a `Cancelled` error, without retrying, and existing `Session`
+/// objects stop working: create new ones afterwards.
+#[napi]
+pub fn shutdown() -> napi::Result<()> {
+    let stream_ids: Vec<u32> = lock_streams().keys().copied().collect();
+    for stream_id in stream_ids {
+        cancel_stream(stream_id)?;
+    }
+    cancel_live_requests();
+    unsafe { apple_ai_shutdown() };
+    lock(&INIT_RESULT).take();
+    // The sessions are gone, so their budgets would only leak
+    lock(&SESSION_BUDGETS).take();
+    Ok(())
+}
+
+/// Machine-readable cause behind `ModelAvailability.available`.
+#[napi(string_enum)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum AvailabilityReason {
+    Available,
+    DeviceNotEligible,
+    AppleIntelligenceNotEnabled,
+    ModelNotReady,
+    /// Not running on macOS, so there is no Apple Intelligence at all.
+    UnsupportedPlatform,
+    Unknown,
+}
+
+impl AvailabilityReason {
+    fn from_code(code: c_int) -> Self {
+        match code {
+            0 => AvailabilityReason::Available,
+            1 => AvailabilityReason::DeviceNotEligible,
+            2 => AvailabilityReason::AppleIntelligenceNotEnabled,
+            3 => AvailabilityReason::ModelNotReady,
+            4 => AvailabilityReason::UnsupportedPlatform,
+            _ => AvailabilityReason::Unknown,
+        }
+    }
 }
 
 #[napi(object)]
 pub struct ModelAvailability {
     pub available: bool,
+    /// Human-readable explanation, suitable for display.
     pub reason: String,
+    pub reason_code: AvailabilityReason,
 }
 
 #[inline(always)]
 fn take_c_string(ptr: *mut c_char) -> String {
+    String::from_utf8_lossy(&take_c_bytes(ptr)).into_owned()
+}
+
+/// Like `take_c_string`, but keeps the raw bytes without UTF-8 decoding.
+#[inline(always)]
+fn take_c_bytes(ptr: *mut c_char) -> Vec<u8> {
     if ptr.is_null() {
-        return String::new();
+        return Vec::new();
     }
     unsafe {
-        let s = CStr::from_ptr(ptr).to_string_lossy().into_owned();
-        libc::free(ptr as *mut _);
-        s
+        let bytes = CStr::from_ptr(ptr).to_bytes().to_vec();
+        apple_ai_free_string(ptr);
+        bytes
     }
 }
 
+fn availability_reason() -> String {
+    unsafe { take_c_string(apple_ai_get_availability_reason()) }
+}
+
+fn availability_reason_code() -> AvailabilityReason {
+    AvailabilityReason::from_code(unsafe { apple_ai_get_availability_reason_code() })
+}
+
 #[napi]
 pub fn check_availability() -> napi::Result<ModelAvailability> {
-    ensure_initialized();
+    // Reported rather than thrown, so apps can feature-detect on any platform
+    if ensure_initialized().is_err() {
+        return Ok(ModelAvailability {
+            available: false,
+            reason: availability_reason(),
+            reason_code: availability_reason_code(),
+        });
+    }
     unsafe {
         let status = apple_ai_check_availability();
         if status == 1 {
             Ok(ModelAvailability {
                 available: true,
-                reason: "Available".to_string(),
+                // The mock backend identifies itself through the reason
+                reason: if cfg!(feature = "mock") {
+                    availability_reason()
+                } else {
+                    "Available".to_string()
+                },
+                reason_code: AvailabilityReason::Available,
             })
         } else {
-            let reason_ptr = apple_ai_get_availability_reason();
-            let reason = take_c_string(reason_ptr);
             Ok(ModelAvailability {
                 available: false,
-                reason,
+                reason: availability_reason(),
+                reason_code: availability_reason_code(),
             })
         }
     }
 }
 
+// ---------------- Logging ----------------
+
+/// A diagnostic from the native layer, passed to the `set_log_callback`
+/// callback.
+#[napi(object)]
+pub struct LogEvent {
+    #[napi(ts_type = "'debug' | 'info' | 'warn' | 'error'")]
+    pub level: String,
+    pub message: String,
+    /// Request id of the call the event is about: the stream id for
+    /// streams, `requestId` on the promise otherwise. Each prompt of a
+    /// `generate_batch` gets its own id, which no promise carries.
+    pub request_id: Option<u32>,
+    /// `GenerationOptions.metadata` of the call the event is about.
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+static LOG_CALLBACK: Mutex<Option<ThreadsafeFunction<LogEvent, ErrorStrategy::Fatal>>> =
+    Mutex::new(None);
+
+/// Receive native diagnostics (initialization, generations starting,
+/// finishing, failing and being retried, stream errors) as
+/// `callback({ level, message })`, e.g. to forward them to an app's logger.
+/// Replaces any previous callback; pass `undefined` to stop logging.
+///
+/// Events are delivered asynchronously on the event loop, and the callback
+/// doesn't keep the process alive.
+#[napi]
+pub fn set_log_callback(
+    env: Env,
+    #[napi(ts_arg_type = "((event: LogEvent) => void) | undefined")] callback: Option<JsFunction>,
+) -> napi::Result<()> {
+    let tsfn = callback
+        .map(|callback| {
+            let mut tsfn: ThreadsafeFunction<LogEvent, ErrorStrategy::Fatal> = callback
+                .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<LogEvent>| {
+                    Ok(vec![ctx.value])
+                })?;
+            tsfn.unref(&env)?;
+            Ok::<_, napi::Error>(tsfn)
+        })
+        .transpose()?;
+    *lock(&LOG_CALLBACK) = tsfn;
+    Ok(())
+}
+
+/// Send an event to the log callback, if any. `message` is only built when
+/// someone is listening.
+fn log_event(level: &str, message: impl FnOnce() -> String) {
+    send_log_event(level, None, None, message);
+}
+
+/// `log_event` about request `request_id`, made with `metadata`.
+fn log_request_event(
+    level: &str,
+    request_id: u32,
+    metadata: Option<&HashMap<String, String>>,
+    message: impl FnOnce() -> String,
+) {
+    send_log_event(level, Some(request_id), metadata, message);
+}
+
+fn send_log_event(
+    level: &str,
+    request_id: Option<u32>,
+    metadata: Option<&HashMap<String, String>>,
+    message: impl FnOnce() -> String,
+) {
+    let callback = lock(&LOG_CALLBACK);
+    if let Some(tsfn) = callback.as_ref() {
+        let event = LogEvent {
+            level: level.to_string(),
+            message: message(),
+            request_id,
+            metadata: metadata.cloned(),
+        };
+        call_log_callback(tsfn, event);
+    }
+}
+
+#[cfg(not(test))]
+fn call_log_callback(tsfn: &ThreadsafeFunction<LogEvent, ErrorStrategy::Fatal>, event: LogEvent) {
+    tsfn.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+}
+
+// Unit tests run without Node, so no callback can be set; leaving out the
+// call keeps N-API out of the test binary, which has nothing to resolve it
+#[cfg(test)]
+fn call_log_callback(_tsfn: &ThreadsafeFunction<LogEvent, ErrorStrategy::Fatal>, _event: LogEvent) {
+}
+
+// ---------------- Model readiness ----------------
+
+/// How often `watch_model_ready` re-checks availability.
+const MODEL_READY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Call `callback` once with the availability as soon as the model can be
+/// used, e.g. after its download finished.
+///
+/// FoundationModels exposes no download progress, only whether the model is
+/// ready, so this polls `check_availability`. If the model can never become
+/// available (`DeviceNotEligible` or `UnsupportedPlatform`), `callback` is
+/// called right away with that result instead. The watch doesn't keep the
+/// process alive.
+///
+/// Returns a function that stops the watch without calling `callback`, for
+/// states that may last indefinitely, like `AppleIntelligenceNotEnabled`.
+#[napi(ts_return_type = "() => void")]
+pub fn watch_model_ready(
+    env: Env,
+    #[napi(ts_arg_type = "(availability: ModelAvailability) => void")] callback: JsFunction,
+) -> napi::Result<JsFunction> {
+    let mut tsfn: ThreadsafeFunction<ModelAvailability, ErrorStrategy::Fatal> = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<ModelAvailability>| {
+            Ok(vec![ctx.value])
+        })?;
+    tsfn.unref(&env)?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let unsubscribe = {
+        let stopped = Arc::clone(&stopped);
+        env.create_function_from_closure("unsubscribe", move |ctx| {
+            stopped.store(true, Ordering::Release);
+            ctx.env.get_undefined()
+        })?
+    };
+    thread::spawn(move || loop {
+        if stopped.load(Ordering::Acquire) {
+            return;
+        }
+        let availability = match check_availability() {
+            Ok(availability) => availability,
+            Err(err) => ModelAvailability {
+                available: false,
+                reason: err.reason,
+                reason_code: AvailabilityReason::Unknown,
+            },
+        };
+        let settled = availability.available
+            || matches!(
+                availability.reason_code,
+                AvailabilityReason::DeviceNotEligible | AvailabilityReason::UnsupportedPlatform
+            );
+        if settled {
+            tsfn.call(availability, ThreadsafeFunctionCallMode::NonBlocking);
+            return;
+        }
+        thread::sleep(MODEL_READY_POLL_INTERVAL);
+    });
+    Ok(unsubscribe)
+}
+
+/// Make sure the on-device model is downloaded or on its way, for first-run
+/// onboarding; pair it with `watch_model_ready`. Safe to call repeatedly.
+///
+/// FoundationModels has no call to start the download: the OS fetches the
+/// model on its own once Apple Intelligence is enabled, pacing it by network,
+/// battery and load. So this succeeds when the model is ready or downloading
+/// (`ModelNotReady`) and throws a `ModelUnavailable` error with the
+/// availability reason when no download can happen: the device is ineligible,
+/// the platform unsupported, or Apple Intelligence still has to be enabled.
+#[napi]
+pub fn request_model_download(env: Env) -> napi::Result<()> {
+    let availability = check_availability()?;
+    match availability.reason_code {
+        AvailabilityReason::Available | AvailabilityReason::ModelNotReady => Ok(()),
+        _ => Err(coded_error(
+            env,
+            GenerationErrorCode::ModelUnavailable,
+            availability.reason,
+        )),
+    }
+}
+
+// ---------------- Prewarm task ----------------
+
+pub struct PrewarmTask;
+
+impl napi::Task for PrewarmTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        if unsafe { apple_ai_prewarm() } {
+            Ok(())
+        } else {
+            Err(napi::Error::from_reason(availability_reason()))
+        }
+    }
+
+    fn resolve(&mut self, _env: Env, _output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(())
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(coded_error(
+            env,
+            GenerationErrorCode::ModelUnavailable,
+            err.reason,
+        ))
+    }
+}
+
+/// Load the model ahead of the first generation to cut first-token latency.
+///
+/// Safe to call repeatedly; later calls resolve immediately. Generating
+/// without prewarming still works, the first request just pays the load cost.
+/// Rejects with the `check_availability` reason if the model is unavailable.
+#[napi]
+pub fn prewarm() -> napi::Result<AsyncTask<PrewarmTask>> {
+    Ok(AsyncTask::new(PrewarmTask))
+}
+
+pub struct InitializeAndPrewarmTask {
+    handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for InitializeAndPrewarmTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        PrewarmTask.compute().inspect_err(|_| {
+            let _ = self
+                .handle
+                .error_code
+                .set(GenerationErrorCode::ModelUnavailable);
+        })?;
+        // Prewarming only starts loading; a first token means it finished
+        probe_generation(None, &self.handle)
+    }
+
+    fn resolve(&mut self, _env: Env, _output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(())
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+/// `initialize` and `prewarm` in one call that resolves once the model is
+/// loaded and has generated, so deployment scripts can wait for readiness.
+///
+/// Rejects with a `ModelUnavailable` error (the init error, or the
+/// `check_availability` reason) instead of waiting when the model can't load.
+#[napi]
+pub fn initialize_and_prewarm() -> AsyncTask<InitializeAndPrewarmTask> {
+    AsyncTask::new(InitializeAndPrewarmTask {
+        handle: RequestHandle::new(),
+    })
+}
+
+/// Supported languages as of the first query (or the last refresh), since
+/// the set rarely changes during a process lifetime.
+static SUPPORTED_LANGUAGES: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// Display names of the languages the model supports.
+///
+/// Cached after the first call; see `refresh_supported_languages`.
 #[napi]
 pub fn get_supported_languages() -> napi::Result<Vec<String>> {
-    ensure_initialized();
+    let mut cache = lock(&SUPPORTED_LANGUAGES);
+    if let Some(langs) = cache.as_ref() {
+        return Ok(langs.clone());
+    }
+    let langs = query_supported_languages()?;
+    *cache = Some(langs.clone());
+    Ok(langs)
+}
+
+/// Drop the cached language lists and query them again, e.g. after a model
+/// update added languages.
+#[napi]
+pub fn refresh_supported_languages() -> napi::Result<Vec<String>> {
+    clear_caches();
+    get_supported_languages()
+}
+
+/// Free the memoized language lists to trim memory while idle; they are
+/// queried again on next use. Unlike `shutdown`, the model and sessions
+/// stay loaded.
+///
+/// These are the only caches: token counting and language detection keep
+/// no state between calls, on either side of the FFI.
+#[napi]
+pub fn clear_caches() {
+    lock(&SUPPORTED_LANGUAGES).take();
+    lock(&SUPPORTED_LANGUAGE_KEYS).take();
+}
+
+fn query_supported_languages() -> napi::Result<Vec<String>> {
+    ensure_initialized()?;
     unsafe {
         let count = apple_ai_get_supported_languages_count();
         let mut langs = Vec::with_capacity(count as usize);
@@ -113,25 +647,3677 @@ pub fn get_supported_languages() -> napi::Result<Vec<String>> {
     }
 }
 
-// ---------------- Async generation tasks ----------------
+#[napi(object)]
+pub struct LanguageInfo {
+    /// BCP-47 tag, e.g. `en-US` or `zh-Hans-CN`.
+    pub tag: String,
+    /// ISO 639 language code, e.g. `en`.
+    pub language: String,
+    /// ISO 3166 region code or UN M.49 area, e.g. `US` or `419`.
+    pub region: Option<String>,
+}
+
+impl LanguageInfo {
+    fn from_tag(tag: String) -> Self {
+        let mut subtags = tag.split(['-', '_']);
+        let language = subtags.next().unwrap_or_default().to_lowercase();
+        // Skip the script, if any: regions are 2 letters or 3 digits
+        let region = subtags
+            .find(|s| {
+                (s.len() == 2 && s.chars().all(|c| c.is_ascii_alphabetic()))
+                    || (s.len() == 3 && s.chars().all(|c| c.is_ascii_digit()))
+            })
+            .map(str::to_uppercase);
+        LanguageInfo {
+            tag,
+            language,
+            region,
+        }
+    }
+}
+
+/// Like `get_supported_languages`, but as parsed language tags instead of
+/// display names.
+#[napi]
+pub fn get_supported_languages_detailed() -> napi::Result<Vec<LanguageInfo>> {
+    ensure_initialized()?;
+    unsafe {
+        let count = apple_ai_get_supported_languages_count();
+        let mut langs = Vec::with_capacity(count.max(0) as usize);
+        for i in 0..count {
+            let tag_ptr = apple_ai_get_supported_language_tag(i);
+            if !tag_ptr.is_null() {
+                langs.push(LanguageInfo::from_tag(take_c_string(tag_ptr)));
+            }
+        }
+        Ok(langs)
+    }
+}
+
+/// Lookup keys of the supported languages: `en` for every English variant,
+/// plus `en-us` for a regional one or `en-*` for one without a region.
+/// Cached like `SUPPORTED_LANGUAGES`.
+static SUPPORTED_LANGUAGE_KEYS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Run `f` on the (possibly freshly queried) lookup keys.
+fn with_supported_language_keys<R>(f: impl FnOnce(&HashSet<String>) -> R) -> napi::Result<R> {
+    let mut cache = lock(&SUPPORTED_LANGUAGE_KEYS);
+    if let Some(keys) = cache.as_ref() {
+        return Ok(f(keys));
+    }
+    let mut keys = HashSet::new();
+    for info in get_supported_languages_detailed()? {
+        let region = info.region.as_deref().unwrap_or("*").to_lowercase();
+        keys.insert(format!("{}-{region}", info.language));
+        keys.insert(info.language);
+    }
+    Ok(f(cache.insert(keys)))
+}
+
+/// Whether the model supports the language of BCP-47 `tag`.
+///
+/// Case-insensitive, and `_` separators are accepted. A bare language
+/// (`en`) matches any of its supported variants; with a region (`en-GB`),
+/// that region or a region-less variant must be supported. Scripts are
+/// ignored.
+#[napi]
+pub fn is_language_supported(tag: String) -> napi::Result<bool> {
+    let query = LanguageInfo::from_tag(tag);
+    with_supported_language_keys(|keys| match query.region {
+        None => keys.contains(&query.language),
+        Some(region) => {
+            let language = query.language;
+            keys.contains(&format!("{language}-{}", region.to_lowercase()))
+                || keys.contains(&format!("{language}-*"))
+        }
+    })
+}
+
+// ---------------- Language detection ----------------
+
+#[napi(object)]
+#[derive(Deserialize)]
+pub struct LanguageCandidate {
+    /// BCP-47 tag, e.g. `en` or `zh-Hant`.
+    pub language: String,
+    /// Likelihood in `0.0..=1.0`.
+    pub confidence: f64,
+}
+
+fn detect_languages(text: String, max_candidates: u32) -> napi::Result<Vec<LanguageCandidate>> {
+    ensure_initialized()?;
+    let c_text = CString::new(text)
+        .map_err(|_| napi::Error::from_reason("Text contained null byte".to_string()))?;
+    let max_candidates = c_int::try_from(max_candidates).unwrap_or(c_int::MAX);
+    let ptr = unsafe { apple_ai_detect_language(c_text.as_ptr(), max_candidates) };
+    if ptr.is_null() {
+        return Err(napi::Error::from_reason(
+            "Language detection is not supported on this device".to_string(),
+        ));
+    }
+    let json = take_c_string(ptr);
+    let candidates: Vec<LanguageCandidate> = serde_json::from_str(&json)
+        .map_err(|e| napi::Error::from_reason(format!("Invalid language detection result: {e}")))?;
+    if candidates.is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Could not determine the language of the text".to_string(),
+        ));
+    }
+    Ok(candidates)
+}
+
+/// Detect the language `text` is written in, as a BCP-47 tag.
+///
+/// Compare against `get_supported_languages` to decide whether the model can
+/// handle the text. Errors if the text is too short or ambiguous to tell, or
+/// if the device can't detect languages.
+#[napi]
+pub fn detect_language(text: String) -> napi::Result<String> {
+    let mut candidates = detect_languages(text, 1)?;
+    Ok(candidates.swap_remove(0).language)
+}
+
+/// Like `detect_language`, but returns up to `max_candidates` (default 3)
+/// languages with their confidence, most likely first.
+#[napi]
+pub fn detect_language_candidates(
+    text: String,
+    max_candidates: Option<u32>,
+) -> napi::Result<Vec<LanguageCandidate>> {
+    detect_languages(text, max_candidates.unwrap_or(3).max(1))
+}
+
+// ---------------- Embeddings ----------------
+
+fn embed(text: &str) -> napi::Result<Vec<f64>> {
+    if text.trim().is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Cannot embed empty text".to_string(),
+        ));
+    }
+    let c_text = CString::new(text)
+        .map_err(|_| napi::Error::from_reason("Text contained null byte".to_string()))?;
+    let ptr = unsafe { apple_ai_generate_embedding(c_text.as_ptr()) };
+    if ptr.is_null() {
+        return Err(napi::Error::from_reason(
+            "Embeddings are not supported for this text's language on this device".to_string(),
+        ));
+    }
+    serde_json::from_str(&take_c_string(ptr))
+        .map_err(|e| napi::Error::from_reason(format!("Invalid embedding result: {e}")))
+}
+
+pub struct EmbeddingTask {
+    pub text: String,
+}
+
+impl napi::Task for EmbeddingTask {
+    type Output = Vec<f64>;
+    type JsValue = Vec<f64>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        embed(&self.text)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// Semantic embedding of `text`, for similarity search and clustering.
+///
+/// FoundationModels has no embedding API, so this uses the OS sentence
+/// embedding (NaturalLanguage) for the text's language. Vectors from
+/// different languages aren't comparable. Rejects if the device has no
+/// embedding for the language.
+#[napi]
+pub fn generate_embedding(text: String) -> AsyncTask<EmbeddingTask> {
+    AsyncTask::new(EmbeddingTask { text })
+}
+
+pub struct EmbeddingsTask {
+    pub texts: Vec<String>,
+}
+
+impl napi::Task for EmbeddingsTask {
+    type Output = Vec<Vec<f64>>;
+    type JsValue = Vec<Vec<f64>>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        self.texts.iter().map(|text| embed(text)).collect()
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// Like `generate_embedding`, one vector per text in order. Rejects if any
+/// text can't be embedded.
+#[napi]
+pub fn generate_embeddings(texts: Vec<String>) -> AsyncTask<EmbeddingsTask> {
+    AsyncTask::new(EmbeddingsTask { texts })
+}
+
+// ---------------- Health check ----------------
+
+/// Generate a single token, which only succeeds once the model is loaded.
+fn probe_generation(timeout_ms: Option<u32>, handle: &Arc<RequestHandle>) -> napi::Result<()> {
+    let options = NativeOptions::new(
+        None,
+        Some(1),
+        Some(GenerationOptions {
+            timeout_ms,
+            ..Default::default()
+        }),
+    )?;
+    run_generation(&options, handle, |options, handle| unsafe {
+        apple_ai_generate_with_instructions(c"".as_ptr(), c"Hi".as_ptr(), options, handle)
+    })?;
+    Ok(())
+}
+
+/// Upper bound on the probe generation of `health_check`.
+const HEALTH_CHECK_TIMEOUT_MS: u32 = 10_000;
+
+#[napi(object)]
+pub struct HealthStatus {
+    pub ok: bool,
+    /// Time the probe took, including any model loading.
+    pub latency_ms: u32,
+    /// Why the probe failed; absent when `ok`.
+    pub reason: Option<String>,
+    /// Set together with `reason` when the generation itself failed.
+    pub code: Option<GenerationErrorCode>,
+}
+
+pub struct HealthTask;
+
+impl napi::Task for HealthTask {
+    type Output = HealthStatus;
+    type JsValue = HealthStatus;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let started = Instant::now();
+        let handle = RequestHandle::new();
+        let result = ensure_initialized()
+            .inspect_err(|_| {
+                let _ = handle.error_code.set(GenerationErrorCode::ModelUnavailable);
+            })
+            .and_then(|()| probe_generation(Some(HEALTH_CHECK_TIMEOUT_MS), &handle));
+        let latency_ms = started.elapsed().as_millis().try_into().unwrap_or(u32::MAX);
+        Ok(match result {
+            Ok(_) => HealthStatus {
+                ok: true,
+                latency_ms,
+                reason: None,
+                code: None,
+            },
+            Err(err) => HealthStatus {
+                ok: false,
+                latency_ms,
+                code: Some(handle.error_code(&err)),
+                reason: Some(err.reason),
+            },
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// Probe that the model actually generates, e.g. for a readiness endpoint.
+///
+/// Unlike `check_availability`, this runs a one-token generation (bounded to
+/// 10 s), so it also catches a model that reports available but fails. Never
+/// rejects: failures are reported in the result.
+#[napi]
+pub fn health_check() -> AsyncTask<HealthTask> {
+    AsyncTask::new(HealthTask)
+}
+
+// ---------------- Model info ----------------
+
+#[napi(object)]
+pub struct ModelInfo {
+    pub name: String,
+    /// Empty when the OS doesn't expose one.
+    pub version: String,
+    pub on_device: bool,
+}
+
+/// Identify the model behind generations, e.g. to record provenance.
+#[napi]
+pub fn get_model_info() -> napi::Result<ModelInfo> {
+    ensure_initialized()?;
+    unsafe {
+        Ok(ModelInfo {
+            name: take_c_string(apple_ai_get_model_name()),
+            version: take_c_string(apple_ai_get_model_version()),
+            // The system model always runs locally
+            on_device: true,
+        })
+    }
+}
+
+/// Bytes of memory the loaded model takes up, to decide whether to
+/// `shutdown()` between bursts of use.
+///
+/// Throws instead of guessing when the OS doesn't report it, which is always
+/// the case today: FoundationModels runs the model in a system process and
+/// exposes no figure for it, and it isn't counted in this process's own
+/// footprint either.
+#[napi]
+pub fn get_model_memory_usage() -> napi::Result<i64> {
+    ensure_initialized()?;
+    match unsafe { apple_ai_get_model_memory_usage() } {
+        bytes if bytes >= 0 => Ok(bytes),
+        _ => Err(napi::Error::from_reason(
+            "The OS doesn't report the model's memory usage".to_string(),
+        )),
+    }
+}
+
+// ---------------- Feature detection ----------------
+
+// Feature ids shared with the Swift layer's `apple_ai_supports_feature`
+const FEATURE_STREAMING: c_int = 0;
+const FEATURE_TOOLS: c_int = 1;
+const FEATURE_VISION: c_int = 2;
+
+/// `false` whenever the library can't be initialized.
+fn supports_feature(feature: c_int) -> bool {
+    ensure_initialized().is_ok() && unsafe { apple_ai_supports_feature(feature) }
+}
+
+/// Whether `generate_response_stream` and the other streaming calls work on
+/// this device right now. `false` when uncertain, e.g. while the model is
+/// unavailable.
+#[napi]
+pub fn supports_streaming() -> bool {
+    supports_feature(FEATURE_STREAMING)
+}
+
+/// Whether `generate_with_tools` works on this device; like
+/// `supports_streaming`.
+#[napi]
+pub fn supports_tools() -> bool {
+    supports_feature(FEATURE_TOOLS)
+}
+
+/// Whether `generate_response_with_image` is accepted. Always `false` for
+/// now: Apple's on-device model takes text only.
+#[napi]
+pub fn supports_vision() -> bool {
+    supports_feature(FEATURE_VISION)
+}
+
+/// Everything apps usually check at startup, from `get_capabilities`.
+#[napi(object)]
+pub struct Capabilities {
+    pub availability: ModelAvailability,
+    /// Absent when the library can't be initialized.
+    pub model: Option<ModelInfo>,
+    /// `0` when the library can't be initialized.
+    pub context_window: u32,
+    /// As from `get_supported_languages`; empty when the library can't be
+    /// initialized.
+    pub supported_languages: Vec<String>,
+    pub supports_streaming: bool,
+    pub supports_tools: bool,
+    pub supports_vision: bool,
+}
+
+/// `check_availability`, `get_model_info`, `get_context_window`,
+/// `get_supported_languages` and the `supports_*` checks in one call, for
+/// feature detection at startup.
+///
+/// Like `check_availability`, reports rather than throws when the library or
+/// model is unavailable. The parts are in-process calls into the Swift layer,
+/// so there's no round trip to save by merging them further.
+#[napi]
+pub fn get_capabilities() -> napi::Result<Capabilities> {
+    let availability = check_availability()?;
+    if ensure_initialized().is_err() {
+        return Ok(Capabilities {
+            availability,
+            model: None,
+            context_window: 0,
+            supported_languages: Vec::new(),
+            supports_streaming: false,
+            supports_tools: false,
+            supports_vision: false,
+        });
+    }
+    Ok(Capabilities {
+        availability,
+        model: Some(get_model_info()?),
+        context_window: get_context_window()?,
+        supported_languages: get_supported_languages()?,
+        supports_streaming: supports_streaming(),
+        supports_tools: supports_tools(),
+        supports_vision: supports_vision(),
+    })
+}
+
+// ---------------- Token counting ----------------
+
+/// Maximum number of tokens the model can handle per request.
+///
+/// The window is shared between the prompt (including instructions and
+/// history) and the generated response; Apple doesn't document separate
+/// input and output limits.
+#[napi]
+pub fn get_context_window() -> napi::Result<u32> {
+    ensure_initialized()?;
+    Ok(unsafe { apple_ai_get_context_window() }.max(0) as u32)
+}
+
+/// Estimate how many tokens `text` takes up in the model's context.
+///
+/// FoundationModels doesn't expose its tokenizer, so this is the same
+/// estimate used for `GenerationResult` usage: roughly four ASCII characters
+/// per token and one token per non-ASCII character.
+#[napi]
+pub fn count_tokens(text: String) -> napi::Result<u32> {
+    ensure_initialized()?;
+    let c_text = CString::new(text)
+        .map_err(|_| napi::Error::from_reason("Text contained null byte".to_string()))?;
+    let count = unsafe { apple_ai_count_tokens(c_text.as_ptr()) };
+    Ok(count.max(0) as u32)
+}
+
+/// Like `count_tokens`, summed over the contents of a `messages_json` history.
+#[napi]
+pub fn count_tokens_for_messages(messages_json: String) -> napi::Result<u32> {
+    ensure_initialized()?;
+    let c_json = CString::new(messages_json)
+        .map_err(|_| napi::Error::from_reason("JSON contained null byte".to_string()))?;
+    let count = unsafe { apple_ai_count_tokens_for_messages(c_json.as_ptr()) };
+    if count < 0 {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Invalid messages JSON".to_string(),
+        ));
+    }
+    Ok(count as u32)
+}
+
+#[napi(object)]
+pub struct GenerationEstimate {
+    /// `count_tokens` of the prompt.
+    pub prompt_tokens: u32,
+    /// `max_tokens`, or the room left in the context window when there is no
+    /// limit.
+    pub max_output_tokens: u32,
+    /// Whether the prompt plus `max_output_tokens` fit in the context window.
+    pub fits_context: bool,
+}
+
+/// Preview a `generate_response` call without running the model: the
+/// estimated prompt size and whether the reply has room in the context
+/// window. `max_tokens` defaults as it does for generations.
+#[napi]
+pub fn estimate_generation(
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+) -> napi::Result<GenerationEstimate> {
+    let max_tokens = validate_max_tokens(max_tokens.or(default_params().max_tokens))?;
+    let prompt_tokens = count_tokens(prompt)?;
+    let context_window = get_context_window()?;
+    let max_output_tokens = match u32::try_from(max_tokens) {
+        Ok(n) if n > 0 => n,
+        _ => context_window.saturating_sub(prompt_tokens),
+    };
+    Ok(GenerationEstimate {
+        prompt_tokens,
+        max_output_tokens,
+        fits_context: prompt_tokens < context_window
+            && prompt_tokens.saturating_add(max_output_tokens) <= context_window,
+    })
+}
+
+/// Reject a prompt of `prompt_tokens` (as counted by the Swift layer;
+/// negative if it couldn't count) that doesn't fit in the context window.
+fn check_context_window(prompt_tokens: c_int, handle: &RequestHandle) -> napi::Result<()> {
+    let limit = unsafe { apple_ai_get_context_window() };
+    if limit > 0 && prompt_tokens > limit {
+        let _ = handle.error_code.set(GenerationErrorCode::ContextOverflow);
+        return Err(napi::Error::from_reason(format!(
+            "Prompt is about {prompt_tokens} tokens, over the {limit}-token context window"
+        )));
+    }
+    Ok(())
+}
+
+// ---------------- Error codes ----------------
+
+/// Machine-readable cause of a failed generation.
+///
+/// Set as the `code` of the error a generation promise rejects with, and as
+/// `StreamChunk.code` when a stream fails.
+#[napi(string_enum)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum GenerationErrorCode {
+    Unknown,
+    ModelUnavailable,
+    /// Apple's safety guardrails blocked the prompt or the response. The
+    /// message includes any detail the framework reports; it doesn't say
+    /// which category was violated.
+    GuardrailViolation,
+    ContextOverflow,
+    UnsupportedLanguage,
+    RateLimited,
+    /// The session is already handling another request.
+    Busy,
+    InvalidInput,
+    DecodingFailure,
+    Refusal,
+    Cancelled,
+    Timeout,
+    /// A `json` response format was requested but the output didn't parse.
+    MalformedJson,
+    /// The model can't take image input.
+    MultimodalUnsupported,
+    /// The model is still downloading: wait (see `watch_model_ready`) and
+    /// retry.
+    ModelNotReady,
+    /// The session used up the budget set with `Session.set_token_budget`.
+    BudgetExceeded,
+}
+
+impl GenerationErrorCode {
+    /// Map the integer code reported by the Swift layer.
+    fn from_code(code: i32) -> Self {
+        match code {
+            1 => GenerationErrorCode::ModelUnavailable,
+            2 => GenerationErrorCode::GuardrailViolation,
+            3 => GenerationErrorCode::ContextOverflow,
+            4 => GenerationErrorCode::UnsupportedLanguage,
+            5 => GenerationErrorCode::RateLimited,
+            6 => GenerationErrorCode::Busy,
+            7 => GenerationErrorCode::InvalidInput,
+            8 => GenerationErrorCode::DecodingFailure,
+            9 => GenerationErrorCode::Refusal,
+            10 => GenerationErrorCode::MultimodalUnsupported,
+            _ => GenerationErrorCode::Unknown,
+        }
+    }
+
+    fn from_status(status: Status) -> Self {
+        match status {
+            Status::InvalidArg => GenerationErrorCode::InvalidInput,
+            Status::Cancelled => GenerationErrorCode::Cancelled,
+            _ => GenerationErrorCode::Unknown,
+        }
+    }
+}
+
+impl AsRef<str> for GenerationErrorCode {
+    fn as_ref(&self) -> &str {
+        match self {
+            GenerationErrorCode::Unknown => "Unknown",
+            GenerationErrorCode::ModelUnavailable => "ModelUnavailable",
+            GenerationErrorCode::ModelNotReady => "ModelNotReady",
+            GenerationErrorCode::BudgetExceeded => "BudgetExceeded",
+            GenerationErrorCode::GuardrailViolation => "GuardrailViolation",
+            GenerationErrorCode::ContextOverflow => "ContextOverflow",
+            GenerationErrorCode::UnsupportedLanguage => "UnsupportedLanguage",
+            GenerationErrorCode::RateLimited => "RateLimited",
+            GenerationErrorCode::Busy => "Busy",
+            GenerationErrorCode::InvalidInput => "InvalidInput",
+            GenerationErrorCode::DecodingFailure => "DecodingFailure",
+            GenerationErrorCode::Refusal => "Refusal",
+            GenerationErrorCode::Cancelled => "Cancelled",
+            GenerationErrorCode::Timeout => "Timeout",
+            GenerationErrorCode::MalformedJson => "MalformedJson",
+            GenerationErrorCode::MultimodalUnsupported => "MultimodalUnsupported",
+        }
+    }
+}
+
+/// `{ code, message }` error payload of the Swift `_v2` entry points and streams.
+#[derive(Deserialize)]
+struct NativeError {
+    code: i32,
+    message: String,
+}
+
+/// Build a JS error whose `code` is `code`, for rejecting a task promise.
+fn coded_error(env: Env, code: GenerationErrorCode, reason: String) -> napi::Error {
+    napi::Error::from(JsError::from(napi::Error::new(code, reason)).into_unknown(env))
+}
+
+// ---------------- Request cancellation ----------------
+
+/// Source of request ids, shared by generations and streams so that an id
+/// names a single call whatever its kind.
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(1);
+
+fn next_request_id() -> u32 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Every `RequestHandle` still alive, by id, so `shutdown` can cancel them.
+static LIVE_REQUESTS: Mutex<Option<HashMap<u32, Weak<RequestHandle>>>> = Mutex::new(None);
+
+/// Opaque handle shared between a task and its `AbortSignal` listener so the
+/// Swift side can be told to stop an in-flight generation.
+pub struct RequestHandle {
+    /// The request id, which also names the generation to Swift.
+    id: u32,
+    cancelled: AtomicBool,
+    finished: AtomicBool,
+    timed_out: AtomicBool,
+    /// Code of the failure reported by Swift (or the timeout), if any.
+    error_code: OnceLock<GenerationErrorCode>,
+}
+
+impl RequestHandle {
+    fn new() -> Arc<Self> {
+        let handle = Arc::new(RequestHandle {
+            id: next_request_id(),
+            cancelled: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            timed_out: AtomicBool::new(false),
+            error_code: OnceLock::new(),
+        });
+        lock(&LIVE_REQUESTS)
+            .get_or_insert_with(HashMap::new)
+            .insert(handle.id, Arc::downgrade(&handle));
+        handle
+    }
+
+    fn cancel(&self) {
+        // Nothing to cancel once the generation has returned
+        if self.finished.load(Ordering::Acquire) {
+            return;
+        }
+        self.cancelled.store(true, Ordering::Release);
+        unsafe { apple_ai_cancel_request(self.id.into()) };
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Cancelled or timed out: either way, Swift was told to stop.
+    fn is_stopped(&self) -> bool {
+        self.timed_out.load(Ordering::Acquire) || self.is_cancelled()
+    }
+
+    /// Code of the error a task failed with.
+    fn error_code(&self, err: &napi::Error) -> GenerationErrorCode {
+        self.error_code
+            .get()
+            .copied()
+            .unwrap_or_else(|| GenerationErrorCode::from_status(err.status))
+    }
+
+    /// Convert the error a task failed with into its coded JS error.
+    fn coded_error(&self, env: Env, err: napi::Error) -> napi::Error {
+        coded_error(env, self.error_code(&err), err.reason)
+    }
+}
+
+impl Drop for RequestHandle {
+    fn drop(&mut self) {
+        if let Some(live) = lock(&LIVE_REQUESTS).as_mut() {
+            live.remove(&self.id);
+        }
+    }
+}
+
+/// Cancel every generation still running, as `shutdown` is about to pull
+/// the backend out from under them.
+fn cancel_live_requests() {
+    // Upgrade under the lock but cancel (and drop) outside it: the last
+    // reference going away takes the lock again
+    let handles: Vec<Arc<RequestHandle>> = lock(&LIVE_REQUESTS)
+        .as_ref()
+        .map(|live| live.values().filter_map(Weak::upgrade).collect())
+        .unwrap_or_default();
+    for handle in handles {
+        handle.cancel();
+    }
+}
+
+fn cancelled_error() -> napi::Error {
+    napi::Error::new(Status::Cancelled, "Generation cancelled".to_string())
+}
+
+fn timeout_error(timeout_ms: u32) -> napi::Error {
+    napi::Error::from_reason(format!("Timeout: generation exceeded {timeout_ms} ms"))
+}
+
+/// Hook an `AbortSignal` up to `handle` and convert it for `AsyncTask`.
+///
+/// napi-rs rejects the promise on abort; the extra `abort` listener is what
+/// actually stops the Swift generation instead of letting it run to the end.
+fn watch_abort_signal(
+    env: &Env,
+    signal: JsObject,
+    handle: &Arc<RequestHandle>,
+) -> napi::Result<AbortSignal> {
+    if signal.get_named_property::<bool>("aborted")? {
+        return Err(napi::Error::new(
+            Status::Cancelled,
+            "AbortError".to_string(),
+        ));
+    }
+
+    let handle = Arc::clone(handle);
+    on_abort(env, &signal, move || handle.cancel())?;
+    unsafe { AbortSignal::from_napi_value(env.raw(), signal.raw()) }
+}
+
+/// Run `abort` once when `signal` aborts, or right away if it already has.
+fn on_abort(env: &Env, signal: &JsObject, abort: impl FnOnce() + 'static) -> napi::Result<()> {
+    if signal.get_named_property::<bool>("aborted")? {
+        abort();
+        return Ok(());
+    }
+    let abort = Cell::new(Some(abort));
+    let listener = env.create_function_from_closure("onAbort", move |ctx| {
+        if let Some(abort) = abort.take() {
+            abort();
+        }
+        ctx.env.get_undefined()
+    })?;
+    let add_event_listener: JsFunction = signal.get_named_property("addEventListener")?;
+    add_event_listener.call(
+        Some(signal),
+        &[
+            env.create_string("abort")?.into_unknown(),
+            listener.into_unknown(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// A task that generates under a single `RequestHandle`.
+pub trait RequestTask: Task {
+    fn handle(&self) -> &Arc<RequestHandle>;
+}
+
+/// An `AsyncTask` whose promise carries the request id as `requestId`, so
+/// callers can match log events to the call before it settles.
+///
+/// Every single-request generation entry point returns one. `generate_batch`
+/// runs many requests under one promise, so its events are matched by the
+/// index they carry instead; the legacy `generate_response_structured` has
+/// no request id at all.
+pub struct TrackedTask<T: Task> {
+    task: AsyncTask<T>,
+    request_id: u32,
+}
+
+impl<T: RequestTask> TrackedTask<T> {
+    pub fn new(task: T) -> Self {
+        Self::with_optional_signal(task, None)
+    }
+
+    pub fn with_optional_signal(task: T, signal: Option<AbortSignal>) -> Self {
+        let request_id = task.handle().id;
+        TrackedTask {
+            task: AsyncTask::with_optional_signal(task, signal),
+            request_id,
+        }
+    }
+}
+
+impl<T: Task> ToNapiValue for TrackedTask<T> {
+    unsafe fn to_napi_value(env: sys::napi_env, val: Self) -> napi::Result<sys::napi_value> {
+        let promise = AsyncTask::to_napi_value(env, val.task)?;
+        let mut object = JsObject::from_napi_value(env, promise)?;
+        object.set_named_property("requestId", val.request_id)?;
+        Ok(promise)
+    }
+}
+
+// ---------------- Generation options ----------------
+
+/// Optional settings accepted as the trailing argument of the generation
+/// entry points. Omitted fields keep the model's default behaviour.
+#[napi(object)]
+#[derive(Default)]
+pub struct GenerationOptions {
+    /// Nucleus sampling: sample only from the smallest set of tokens whose
+    /// cumulative probability reaches `top_p`. Must be in `0.0..=1.0`.
+    pub top_p: Option<f64>,
+    /// Seed for the sampler. With `temperature > 0` the same prompt, options
+    /// and seed yield the same text. Apple's sampler honours the seed, but
+    /// only guarantees this for the same OS and model version.
+    pub seed: Option<i64>,
+    /// Generation halts as soon as one of these appears; the matched sequence
+    /// is not included in the output (or in any streamed chunk).
+    pub stop_sequences: Option<Vec<String>>,
+    /// Give up after this many milliseconds: the generation is cancelled and
+    /// the call rejects with a `Timeout` error (streams receive it as their
+    /// final error). Partial output is discarded.
+    pub timeout_ms: Option<u32>,
+    /// Streams only: end the stream with a `Timeout` error if no chunk
+    /// arrives for this many milliseconds, for when the model stalls without
+    /// finishing. Time a `StreamReader` spends waiting for its consumer
+    /// counts as idle.
+    pub idle_timeout_ms: Option<u32>,
+    /// Streams only: how many chunks may wait for a slow consumer before
+    /// generation pauses until it catches up, bounding memory use. Unbounded
+    /// for stream callbacks by default; replaces the default of 16 for
+    /// `StreamReader`s.
+    pub stream_buffer_size: Option<u32>,
+    /// Accepted for compatibility with OpenAI-style callers. Apple's model
+    /// exposes no repetition penalties (and no logits to approximate them
+    /// with), so only `0` is allowed; must be in `-2.0..=2.0`.
+    pub frequency_penalty: Option<f64>,
+    /// Same constraints as `frequency_penalty`.
+    pub presence_penalty: Option<f64>,
+    /// Strip null bytes from the prompt and instructions instead of
+    /// rejecting them. They are removed rather than replaced with spaces, so
+    /// text around them is joined as if they weren't there. Defaults to
+    /// `false`.
+    pub sanitize: Option<bool>,
+    /// `json` asks the model for a bare JSON object and rejects with a
+    /// `MalformedJson` error if the response doesn't parse (stream output
+    /// isn't checked). Lighter than `generate_structured`, but without any
+    /// schema. Defaults to `text`.
+    #[napi(ts_type = "'text' | 'json'")]
+    pub response_format: Option<String>,
+    /// Retry a generation this many times (with exponential backoff from
+    /// 200 ms to 10 s) when it fails transiently: the model is rate limited or busy,
+    /// or returned nothing. Other failures are never retried. Streams don't
+    /// retry. Defaults to 0.
+    pub max_retries: Option<u32>,
+    /// Accepted for compatibility with OpenAI-style callers. Apple's model
+    /// doesn't expose token log probabilities, so only `false` is allowed;
+    /// `true` rejects up front rather than returning made-up numbers.
+    pub include_logprobs: Option<bool>,
+    /// Same constraints as `include_logprobs`: only `0` is allowed.
+    pub top_logprobs: Option<u32>,
+    /// Estimate the prompt's tokens (as `count_tokens` does) and reject with
+    /// `ContextOverflow` before generating if they exceed the context window.
+    /// Set to `false` to leave the check to the model. Defaults to `true`.
+    /// Sessions count only the new prompt; a batch checks each prompt on its
+    /// own, failing just that item.
+    pub check_context_window: Option<bool>,
+    /// Resolve `generate_response` and `generate_response_with_instructions`
+    /// to a `Buffer` of UTF-8 bytes instead of a string, which avoids copying
+    /// long outputs into a JS string. Streams deliver each delta as a
+    /// `Buffer` in `StreamChunk.buffer` instead. Other calls ignore it.
+    /// Defaults to `false`.
+    pub as_buffer: Option<bool>,
+    /// Caller-defined tags, such as a request id, attached as-is to this
+    /// call's log events and stream chunks for correlation. Never sent to
+    /// the model.
+    pub metadata: Option<HashMap<String, String>>,
+    /// Cut the output after this many characters, whatever `max_tokens`
+    /// allows: results are truncated (with finish reason `length`) and
+    /// streams end early with a final `done` event once they reach it. The
+    /// cut falls on a character boundary but may be mid-word or
+    /// mid-sentence. Not allowed with the `json` response format.
+    pub max_chars: Option<u32>,
+    /// Streams only: start with an event of kind `prompt` whose `text` is
+    /// the prompt as handed to the model, language and JSON instructions
+    /// included, to debug prompt construction. History streams echo the
+    /// messages JSON with the last message completed that way; session
+    /// streams put the session's instructions, if any, before the prompt,
+    /// separated by a blank line. Defaults to `false`.
+    pub echo_prompt: Option<bool>,
+    /// Streams only: report a `StreamTimings` breakdown on the final event.
+    /// Defaults to `false`, which skips recording a timestamp per chunk.
+    pub stream_timings: Option<bool>,
+    /// BCP-47 tag of the language to reply in, whatever the prompt's
+    /// language. Must pass `is_language_supported`.
+    pub language: Option<String>,
+}
+
+/// Temperature and token limit used when a call doesn't pass them.
+#[napi(object)]
+#[derive(Clone, Default)]
+pub struct DefaultParams {
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<i32>,
+}
+
+static DEFAULT_PARAMS: Mutex<DefaultParams> = Mutex::new(DefaultParams {
+    temperature: None,
+    max_tokens: None,
+});
+
+fn default_params() -> DefaultParams {
+    lock(&DEFAULT_PARAMS).clone()
+}
+
+/// Set the temperature and `max_tokens` that generations use when the call
+/// omits them; arguments passed per call still win. `None` restores the
+/// built-in default for that parameter.
+#[napi]
+pub fn set_default_params(temperature: Option<f64>, max_tokens: Option<i32>) -> napi::Result<()> {
+    validate_temperature(temperature)?;
+    validate_max_tokens(max_tokens)?;
+    *lock(&DEFAULT_PARAMS) = DefaultParams {
+        temperature,
+        max_tokens,
+    };
+    Ok(())
+}
+
+/// The defaults set by `set_default_params`.
+#[napi]
+pub fn get_default_params() -> DefaultParams {
+    default_params()
+}
+
+// ---------------- Sampling presets ----------------
+
+/// Presets every app has: `(name, temperature, top_p)`.
+const BUILT_IN_PRESETS: [(&str, f64, f64); 3] = [
+    ("creative", 1.2, 0.95),
+    ("balanced", 0.7, 0.9),
+    ("precise", 0.2, 0.5),
+];
+
+/// Presets added by `register_preset`, by name.
+static CUSTOM_PRESETS: Mutex<Option<HashMap<String, (f64, f64)>>> = Mutex::new(None);
+
+/// The temperature and top_p of the preset called `name`.
+fn preset(name: &str) -> napi::Result<(f64, f64)> {
+    let custom = lock(&CUSTOM_PRESETS)
+        .as_ref()
+        .and_then(|presets| presets.get(name).copied());
+    custom
+        .or_else(|| {
+            BUILT_IN_PRESETS
+                .iter()
+                .find(|(preset, ..)| *preset == name)
+                .map(|&(_, temperature, top_p)| (temperature, top_p))
+        })
+        .ok_or_else(|| napi::Error::new(Status::InvalidArg, format!("Unknown preset '{name}'")))
+}
+
+/// Add a sampling preset for `generate_with_preset`, or replace one with the
+/// same name (including the built-in `creative`, `balanced` and `precise`).
+#[napi]
+pub fn register_preset(name: String, temperature: f64, top_p: f64) -> napi::Result<()> {
+    if name.is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Preset name must not be empty".to_string(),
+        ));
+    }
+    validate_temperature(Some(temperature))?;
+    validate_top_p(Some(top_p))?;
+    lock(&CUSTOM_PRESETS)
+        .get_or_insert_with(HashMap::new)
+        .insert(name, (temperature, top_p));
+    Ok(())
+}
+
+/// Names of all presets, built-in first, then custom ones in name order.
+#[napi]
+pub fn get_preset_names() -> Vec<String> {
+    let mut custom: Vec<String> = lock(&CUSTOM_PRESETS)
+        .as_ref()
+        .map(|presets| presets.keys().cloned().collect())
+        .unwrap_or_default();
+    custom.sort();
+    let mut names: Vec<String> = BUILT_IN_PRESETS
+        .iter()
+        .map(|(name, ..)| name.to_string())
+        .collect();
+    custom.retain(|name| !names.contains(name));
+    names.extend(custom);
+    names
+}
+
+/// Validated settings handed to the Swift `_v2` entry points as JSON.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NativeOptions {
+    temperature: f64,
+    max_tokens: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    /// Enforced on the Rust side, so not sent to Swift.
+    #[serde(skip)]
+    timeout_ms: Option<u32>,
+    #[serde(skip)]
+    idle_timeout_ms: Option<u32>,
+    #[serde(skip)]
+    stream_buffer_size: Option<u32>,
+    #[serde(skip)]
+    sanitize: bool,
+    /// `Some("json")`, or `None` for text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<String>,
+    #[serde(skip)]
+    max_retries: u32,
+    #[serde(skip)]
+    check_context_window: bool,
+    #[serde(skip)]
+    as_buffer: bool,
+    #[serde(skip)]
+    metadata: Option<HashMap<String, String>>,
+    #[serde(skip)]
+    max_chars: Option<u32>,
+    /// Swift echoes the prompt it builds, suffixes and all.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    echo_prompt: bool,
+    #[serde(skip)]
+    stream_timings: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    /// Always pick the most likely token (overrides temperature and top_p).
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    greedy: bool,
+}
+
+impl NativeOptions {
+    fn new(
+        temperature: Option<f64>,
+        max_tokens: Option<i32>,
+        options: Option<GenerationOptions>,
+    ) -> napi::Result<Self> {
+        let options = options.unwrap_or_default();
+        let defaults = default_params();
+        let temperature = validate_temperature(temperature.or(defaults.temperature))?;
+        let max_tokens = validate_max_tokens(max_tokens.or(defaults.max_tokens))?;
+        validate_penalty("frequency_penalty", options.frequency_penalty)?;
+        validate_penalty("presence_penalty", options.presence_penalty)?;
+        if options.include_logprobs == Some(true) || options.top_logprobs.is_some_and(|n| n > 0) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "logprobs are not supported by Apple's on-device model".to_string(),
+            ));
+        }
+        validate_top_p(options.top_p)?;
+        let seed = options
+            .seed
+            .map(|seed| {
+                u64::try_from(seed).map_err(|_| {
+                    napi::Error::new(
+                        Status::InvalidArg,
+                        format!("seed must be non-negative, got {seed}"),
+                    )
+                })
+            })
+            .transpose()?;
+        let stop_sequences = options.stop_sequences.unwrap_or_default();
+        for stop in &stop_sequences {
+            if stop.is_empty() {
+                return Err(napi::Error::new(
+                    Status::InvalidArg,
+                    "Stop sequences must not be empty".to_string(),
+                ));
+            }
+            if stop.contains('\0') {
+                return Err(napi::Error::from_reason(
+                    "Stop sequence contained null byte".to_string(),
+                ));
+            }
+        }
+        if options.stream_buffer_size == Some(0) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "stream_buffer_size must be at least 1".to_string(),
+            ));
+        }
+        let response_format = match options.response_format.as_deref() {
+            None | Some("text") => None,
+            Some("json") => Some("json".to_string()),
+            Some(other) => {
+                return Err(napi::Error::new(
+                    Status::InvalidArg,
+                    format!("response_format must be 'text' or 'json', got '{other}'"),
+                ))
+            }
+        };
+        if response_format.is_some() && options.max_chars.is_some() {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "max_chars would truncate a json response".to_string(),
+            ));
+        }
+        if let Some(language) = &options.language {
+            if !is_language_supported(language.clone())? {
+                return Err(napi::Error::new(
+                    Status::InvalidArg,
+                    format!("language '{language}' is not supported by the model"),
+                ));
+            }
+        }
+        Ok(NativeOptions {
+            temperature,
+            max_tokens,
+            top_p: options.top_p,
+            seed,
+            stop_sequences,
+            timeout_ms: options.timeout_ms,
+            idle_timeout_ms: options.idle_timeout_ms,
+            stream_buffer_size: options.stream_buffer_size,
+            sanitize: options.sanitize.unwrap_or(false),
+            response_format,
+            max_retries: options.max_retries.unwrap_or(0),
+            check_context_window: options.check_context_window.unwrap_or(true),
+            as_buffer: options.as_buffer.unwrap_or(false),
+            metadata: options.metadata,
+            max_chars: options.max_chars,
+            echo_prompt: options.echo_prompt.unwrap_or(false),
+            stream_timings: options.stream_timings.unwrap_or(false),
+            language: options.language,
+            greedy: false,
+        })
+    }
+
+    /// Settings for greedy decoding; sampling options are rejected since
+    /// they would have no effect.
+    fn greedy(max_tokens: Option<i32>, options: Option<GenerationOptions>) -> napi::Result<Self> {
+        if let Some(options) = &options {
+            if options.top_p.is_some() || options.seed.is_some() {
+                return Err(napi::Error::new(
+                    Status::InvalidArg,
+                    "top_p and seed don't apply to deterministic generation".to_string(),
+                ));
+            }
+        }
+        Ok(NativeOptions {
+            greedy: true,
+            ..NativeOptions::new(Some(0.0), max_tokens, options)?
+        })
+    }
+
+    /// Convert prompt or instructions text (named `what` in errors) for the
+    /// FFI, stripping null bytes if `sanitize` is set.
+    fn c_text(&self, text: impl Into<Vec<u8>>, what: &str) -> napi::Result<CString> {
+        let mut text = text.into();
+        if self.sanitize {
+            text.retain(|&b| b != 0);
+        }
+        CString::new(text)
+            .map_err(|_| napi::Error::from_reason(format!("{what} contained null byte")))
+    }
+
+    fn to_c_string(&self) -> napi::Result<CString> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| napi::Error::from_reason(format!("Failed to encode options: {e}")))?;
+        CString::new(json)
+            .map_err(|_| napi::Error::from_reason("Options contained null byte".to_string()))
+    }
+}
+
+/// Cancel `handle` unless the returned sender is dropped within `timeout_ms`.
+fn start_watchdog(handle: &Arc<RequestHandle>, timeout_ms: u32) -> mpsc::Sender<()> {
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let handle = Arc::clone(handle);
+    thread::spawn(move || {
+        let timeout = Duration::from_millis(timeout_ms.into());
+        if done_rx.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout)
+            && !handle.finished.load(Ordering::Acquire)
+        {
+            handle.timed_out.store(true, Ordering::Release);
+            handle.cancel();
+        }
+    });
+    done_tx
+}
+
+/// Temperatures accepted by every entry point; `None` means 0.0 (greedy).
+const TEMPERATURE_RANGE: std::ops::RangeInclusive<f64> = 0.0..=2.0;
+
+fn validate_temperature(temperature: Option<f64>) -> napi::Result<f64> {
+    let temperature = temperature.unwrap_or(0.0);
+    if !TEMPERATURE_RANGE.contains(&temperature) {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!(
+                "temperature must be between {:?} and {:?}, got {temperature}",
+                TEMPERATURE_RANGE.start(),
+                TEMPERATURE_RANGE.end()
+            ),
+        ));
+    }
+    Ok(temperature)
+}
+
+fn validate_top_p(top_p: Option<f64>) -> napi::Result<()> {
+    match top_p {
+        Some(top_p) if !(0.0..=1.0).contains(&top_p) => Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("top_p must be between 0.0 and 1.0, got {top_p}"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// `max_tokens` as sent to Swift: `0`, like `None`, means no limit beyond
+/// the model's own (the context window), so output is never truncated.
+fn validate_max_tokens(max_tokens: Option<i32>) -> napi::Result<i32> {
+    match max_tokens {
+        Some(n) if n < 0 => Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("max_tokens must be positive, or 0 for no limit, got {n}"),
+        )),
+        n => Ok(n.unwrap_or(0)),
+    }
+}
+
+/// Penalties must be in range, and zero since the model can't apply them.
+fn validate_penalty(name: &str, penalty: Option<f64>) -> napi::Result<()> {
+    match penalty {
+        Some(p) if !(-2.0..=2.0).contains(&p) => Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("{name} must be between -2.0 and 2.0, got {p}"),
+        )),
+        Some(p) if p != 0.0 => Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("{name} is not supported by Apple's on-device model"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// ---------------- Concurrency limit ----------------
+
+struct ConcurrencyLimit {
+    /// `None` for unlimited.
+    max: Option<u32>,
+    active: u32,
+}
+
+static CONCURRENCY: Mutex<ConcurrencyLimit> = Mutex::new(ConcurrencyLimit {
+    max: None,
+    active: 0,
+});
+static CONCURRENCY_FREED: Condvar = Condvar::new();
+
+/// A slot among the generations allowed to run at once, freed on drop.
+struct GenerationPermit;
+
+impl GenerationPermit {
+    fn acquire() -> Self {
+        let mut limit = lock(&CONCURRENCY);
+        while limit.max.is_some_and(|max| limit.active >= max) {
+            limit = wait(&CONCURRENCY_FREED, limit);
+        }
+        limit.active += 1;
+        GenerationPermit
+    }
+}
+
+impl Drop for GenerationPermit {
+    fn drop(&mut self) {
+        lock(&CONCURRENCY).active -= 1;
+        CONCURRENCY_FREED.notify_one();
+    }
+}
+
+/// Run at most `n` non-streaming generations at once; `0` removes the limit
+/// (the default).
+///
+/// Generations over the limit wait for a running one to finish rather than
+/// fail. Each waiting one still occupies a libuv worker thread, which can
+/// delay other async work while many are queued. Streams aren't limited.
+#[napi]
+pub fn set_max_concurrency(n: u32) {
+    lock(&CONCURRENCY).max = (n > 0).then_some(n);
+    // A raised limit may admit several waiting generations
+    CONCURRENCY_FREED.notify_all();
+}
+
+/// Run a non-streaming generation, honouring cancellation through `handle`.
+///
+/// `generate` receives the encoded options and the request handle id and
+/// performs the actual FFI call. It runs again, up to `max_retries` times,
+/// while the failure is transient.
+fn run_generation(
+    options: &NativeOptions,
+    handle: &Arc<RequestHandle>,
+    generate: impl Fn(*const c_char, u64) -> *mut c_char,
+) -> napi::Result<GenerationResult> {
+    let metadata = options.metadata.as_ref();
+    log_request_event("debug", handle.id, metadata, || {
+        format!("Generation {} started", handle.id)
+    });
+    let result = generate_with_retries(options, handle, generate);
+    match &result {
+        Ok(result) => log_request_event("debug", handle.id, metadata, || {
+            format!(
+                "Generation {} finished in {} ms",
+                handle.id, result.latency_ms
+            )
+        }),
+        Err(err) => log_request_event("error", handle.id, metadata, || {
+            format!("Generation {} failed: {}", handle.id, err.reason)
+        }),
+    }
+    result
+}
+
+fn generate_with_retries(
+    options: &NativeOptions,
+    handle: &Arc<RequestHandle>,
+    generate: impl Fn(*const c_char, u64) -> *mut c_char,
+) -> napi::Result<GenerationResult> {
+    let c_options = options.to_c_string()?;
+    let _permit = GenerationPermit::acquire();
+    if handle.is_cancelled() {
+        return Err(cancelled_error());
+    }
+    let watchdog = options.timeout_ms.map(|ms| start_watchdog(handle, ms));
+    let started = Instant::now();
+    let mut retries = 0;
+    let raw = loop {
+        let result_ptr = generate(c_options.as_ptr(), handle.id.into());
+        let raw = (!result_ptr.is_null()).then(|| take_c_string(result_ptr));
+        if retries >= options.max_retries || !is_transient_failure(raw.as_deref(), handle) {
+            break raw;
+        }
+        let delay = (RETRY_BASE_DELAY * 2u32.saturating_pow(retries)).min(RETRY_MAX_DELAY);
+        log_request_event("warn", handle.id, options.metadata.as_ref(), || {
+            format!(
+                "Generation {} failed transiently, retrying in {} ms",
+                handle.id,
+                delay.as_millis()
+            )
+        });
+        thread::sleep(delay);
+        if handle.is_stopped() {
+            break raw;
+        }
+        retries += 1;
+    };
+    let elapsed = started.elapsed();
+    handle.finished.store(true, Ordering::Release);
+    drop(watchdog);
+    if handle.timed_out.load(Ordering::Acquire) {
+        let _ = handle.error_code.set(GenerationErrorCode::Timeout);
+        return Err(timeout_error(options.timeout_ms.unwrap_or_default()));
+    }
+    if handle.is_cancelled() {
+        return Err(cancelled_error());
+    }
+    if let Some(err) = model_not_ready_error(raw.as_deref(), handle) {
+        return Err(err);
+    }
+    let Some(raw) = raw else {
+        return Err(napi::Error::from_reason(
+            "Generation returned null".to_string(),
+        ));
+    };
+    let mut result = parse_generation_result(raw, handle)?;
+    if options.response_format.is_some() && result.tool_call.is_none() {
+        result.text = validate_json_response(&result.text, handle)?;
+    }
+    result.latency_ms = elapsed.as_millis().try_into().unwrap_or(u32::MAX);
+    result.tokens_per_second = tokens_per_second(result.completion_tokens, elapsed);
+    result.on_device = unsafe { apple_ai_last_generation_on_device() };
+    if let Some(max_chars) = options.max_chars {
+        if truncate_chars(&mut result.text, max_chars as usize) {
+            result.finish_reason = "length".to_string();
+        }
+    }
+    if result.finish_reason.is_empty() {
+        result.finish_reason =
+            natural_finish_reason(result.completion_tokens, options.max_tokens).to_string();
+    }
+    Ok(result)
+}
+
+/// A `ModelNotReady` error if a generation that returned `raw` failed because
+/// the model is still downloading, rather than for good.
+///
+/// FoundationModels exposes no download progress, so the message is the
+/// availability reason, which asks to wait and retry.
+fn model_not_ready_error(raw: Option<&str>, handle: &RequestHandle) -> Option<napi::Error> {
+    let unavailable = raw.is_none_or(|raw| {
+        serde_json::from_str::<NativeError>(raw).is_ok_and(|error| {
+            GenerationErrorCode::from_code(error.code) == GenerationErrorCode::ModelUnavailable
+        })
+    });
+    if !unavailable
+        || !matches!(
+            availability_reason_code(),
+            AvailabilityReason::ModelNotReady
+        )
+    {
+        return None;
+    }
+    let _ = handle.error_code.set(GenerationErrorCode::ModelNotReady);
+    Some(napi::Error::from_reason(availability_reason()))
+}
+
+/// Delay before the first retry; it doubles with every further one, up to
+/// `RETRY_MAX_DELAY`.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Whether a failed attempt is worth retrying: no result at all, or the model
+/// being rate limited or busy. Guardrail violations and the like never are,
+/// and neither is anything once `handle` was cancelled or timed out, which
+/// is what usually leaves no result.
+fn is_transient_failure(raw: Option<&str>, handle: &RequestHandle) -> bool {
+    if handle.is_stopped() {
+        return false;
+    }
+    let Some(raw) = raw else {
+        return true;
+    };
+    serde_json::from_str::<NativeError>(raw).is_ok_and(|error| {
+        matches!(
+            GenerationErrorCode::from_code(error.code),
+            GenerationErrorCode::RateLimited | GenerationErrorCode::Busy
+        )
+    })
+}
+
+/// Check a `json` response parses, returning it without any code fence the
+/// model wrapped it in.
+fn validate_json_response(text: &str, handle: &RequestHandle) -> napi::Result<String> {
+    let trimmed = text.trim();
+    let unfenced = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map_or(trimmed, str::trim);
+    match serde_json::from_str::<serde_json::Value>(unfenced) {
+        Ok(_) => Ok(unfenced.to_string()),
+        Err(e) => {
+            let _ = handle.error_code.set(GenerationErrorCode::MalformedJson);
+            Err(napi::Error::from_reason(format!(
+                "Response is not valid JSON: {e}"
+            )))
+        }
+    }
+}
+
+// ---------------- Chat messages ----------------
+
+/// One turn of a conversation, as sent to the Swift side in `messages_json`.
+#[napi(object)]
+#[derive(Serialize, Deserialize)]
+pub struct ChatMessage {
+    #[napi(ts_type = "'system' | 'user' | 'assistant'")]
+    pub role: String,
+    pub content: String,
+    /// Participant who wrote a `user` or `assistant` turn, for conversations
+    /// with several people. The model sees it as a `name: ` prefix on the
+    /// content, since Apple's transcript has no speaker field. Ignored on
+    /// `system` messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+const CHAT_ROLES: [&str; 3] = ["system", "user", "assistant"];
+
+/// Reject histories the Swift side can't handle with an error naming the
+/// offending message.
+fn validate_messages(messages: &[ChatMessage]) -> napi::Result<()> {
+    let invalid = |msg: String| napi::Error::new(Status::InvalidArg, msg);
+    if messages.is_empty() {
+        return Err(invalid("Messages must not be empty".to_string()));
+    }
+    for (i, message) in messages.iter().enumerate() {
+        if !CHAT_ROLES.contains(&message.role.as_str()) {
+            return Err(invalid(format!(
+                "Message {i} has invalid role {:?} (expected system, user or assistant)",
+                message.role
+            )));
+        }
+        if message.content.is_empty() {
+            return Err(invalid(format!("Message {i} has empty content")));
+        }
+        if message
+            .name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            return Err(invalid(format!("Message {i} has an empty name")));
+        }
+    }
+    Ok(())
+}
+
+/// Parse and validate `messages_json`, returning it re-serialized as a C string.
+fn validated_messages_json(messages_json: &str) -> napi::Result<CString> {
+    let messages: Vec<ChatMessage> = serde_json::from_str(messages_json)
+        .map_err(|e| napi::Error::new(Status::InvalidArg, format!("Invalid messages JSON: {e}")))?;
+    validate_messages(&messages)?;
+    let json = serde_json::to_string(&messages)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to encode messages: {e}")))?;
+    CString::new(json).map_err(|_| napi::Error::from_reason("JSON contained null byte".to_string()))
+}
+
+// ---------------- Prompt templates ----------------
+
+/// Substitute `{{name}}` placeholders in `template` with `variables`.
+///
+/// Whitespace inside the braces is ignored and substituted values aren't
+/// scanned again. Errors if a placeholder has no variable, a `{{` is never
+/// closed, or the result contains a null byte.
+#[napi]
+pub fn render_prompt(template: String, variables: HashMap<String, String>) -> napi::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or_else(|| {
+            napi::Error::new(
+                Status::InvalidArg,
+                "Unclosed '{{' in prompt template".to_string(),
+            )
+        })?;
+        let name = after[..end].trim();
+        let value = variables.get(name).ok_or_else(|| {
+            napi::Error::new(
+                Status::InvalidArg,
+                format!("No value for placeholder '{{{{{name}}}}}'"),
+            )
+        })?;
+        rendered.push_str(value);
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    if rendered.contains('\0') {
+        return Err(napi::Error::from_reason(
+            "Rendered prompt contained null byte".to_string(),
+        ));
+    }
+    Ok(rendered)
+}
+
+// ---------------- Async generation tasks ----------------
+
+pub struct GenerateTask {
+    /// System instructions; empty for a plain prompt.
+    pub instructions: String,
+    /// UTF-8 text, except from `generate_response_bytes`.
+    pub prompt: Vec<u8>,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for GenerateTask {
+    type Output = String;
+    type JsValue = JsUnknown;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        let c_instructions = self
+            .options
+            .c_text(std::mem::take(&mut self.instructions), "Instructions")?;
+        let c_prompt = self
+            .options
+            .c_text(std::mem::take(&mut self.prompt), "Prompt")?;
+        if self.options.check_context_window {
+            let prompt_tokens = unsafe {
+                apple_ai_count_tokens(c_instructions.as_ptr())
+                    .saturating_add(apple_ai_count_tokens(c_prompt.as_ptr()))
+            };
+            check_context_window(prompt_tokens, &self.handle)?;
+        }
+        run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_generate_with_instructions(
+                c_instructions.as_ptr(),
+                c_prompt.as_ptr(),
+                options,
+                handle,
+            )
+        })
+        .map(|result| result.text)
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        if self.options.as_buffer {
+            Ok(env
+                .create_buffer_with_data(output.into_bytes())?
+                .into_raw()
+                .into_unknown())
+        } else {
+            Ok(env.create_string(&output)?.into_unknown())
+        }
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for GenerateTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Generate a response for `prompt`.
+///
+/// Pass an `AbortSignal` to cancel: the promise rejects with a `Cancelled`
+/// error and the native generation is stopped. The promise's `requestId`
+/// is the id the call's log events carry.
+#[napi(ts_return_type = "Promise<string | Buffer> & { requestId: number }")]
+pub fn generate_response(
+    env: Env,
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "AbortSignal | undefined")] signal: Option<JsObject>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateTask>> {
+    let options = NativeOptions::new(temperature, max_tokens, options)?;
+    let handle = RequestHandle::new();
+    let signal = signal
+        .map(|signal| watch_abort_signal(&env, signal, &handle))
+        .transpose()?;
+    let task = GenerateTask {
+        instructions: String::new(),
+        prompt: prompt.into_bytes(),
+        options,
+        handle,
+    };
+    Ok(TrackedTask::with_optional_signal(task, signal))
+}
+
+/// Blocking form of `generate_response`, for small scripts.
+///
+/// Runs the generation on the calling thread, which blocks the event loop
+/// until it finishes: never use it in servers or UIs. `generate_response`
+/// remains the recommended path.
+#[napi]
+pub fn generate_response_sync(
+    env: Env,
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<String> {
+    let mut task = GenerateTask {
+        instructions: String::new(),
+        prompt: prompt.into_bytes(),
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    napi::Task::compute(&mut task).map_err(|err| task.handle.coded_error(env, err))
+}
+
+/// `generate_response` for a prompt already encoded by the caller, passed to
+/// the model as-is instead of through a JS string. Null bytes are rejected
+/// (or stripped with `sanitize`) as for string prompts. The model reads text,
+/// so bytes that aren't valid UTF-8 arrive as U+FFFD replacement characters.
+#[napi(ts_return_type = "Promise<string | Buffer> & { requestId: number }")]
+pub fn generate_response_bytes(
+    prompt: Buffer,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateTask>> {
+    let task = GenerateTask {
+        instructions: String::new(),
+        prompt: prompt.into(),
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+/// Generate a response to `prompt` under persistent system `instructions`
+/// (persona, rules, output style), kept separate from the user turn.
+#[napi(ts_return_type = "Promise<string | Buffer> & { requestId: number }")]
+pub fn generate_response_with_instructions(
+    instructions: String,
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateTask>> {
+    let task = GenerateTask {
+        instructions,
+        prompt: prompt.into_bytes(),
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+/// Generate a response with greedy decoding: the model always takes its most
+/// likely next token, so the same prompt gives the same output on the same OS
+/// and model version. For extraction and classification.
+///
+/// There is no temperature to pass, and `top_p` or `seed` options are
+/// rejected.
+#[napi(ts_return_type = "Promise<string | Buffer> & { requestId: number }")]
+pub fn generate_deterministic(
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateTask>> {
+    let task = GenerateTask {
+        instructions: String::new(),
+        prompt: prompt.into_bytes(),
+        options: NativeOptions::greedy(max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+/// `generate_response` with the temperature and top_p of a named preset:
+/// `creative`, `balanced`, `precise`, or one added with `register_preset`.
+/// Unknown names reject, as does a `top_p` option, since the preset sets it.
+#[napi(ts_return_type = "Promise<string | Buffer> & { requestId: number }")]
+pub fn generate_with_preset(
+    prompt: String,
+    preset: String,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateTask>> {
+    let (temperature, top_p) = self::preset(&preset)?;
+    let mut options = options.unwrap_or_default();
+    if options.top_p.is_some() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("top_p is set by the '{preset}' preset"),
+        ));
+    }
+    options.top_p = Some(top_p);
+    let task = GenerateTask {
+        instructions: String::new(),
+        prompt: prompt.into_bytes(),
+        options: NativeOptions::new(Some(temperature), max_tokens, Some(options))?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+// Task for history
+pub struct GenerateHistoryTask {
+    pub messages_json: String,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for GenerateHistoryTask {
+    type Output = String;
+    type JsValue = JsString;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        let c_json = validated_messages_json(&self.messages_json)?;
+        if self.options.check_context_window {
+            let prompt_tokens = unsafe { apple_ai_count_tokens_for_messages(c_json.as_ptr()) };
+            check_context_window(prompt_tokens, &self.handle)?;
+        }
+        run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_generate_response_with_history_v2(c_json.as_ptr(), options, handle)
+        })
+        .map(|result| result.text)
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        env.create_string(&output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for GenerateHistoryTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+#[napi(ts_return_type = "Promise<string> & { requestId: number }")]
+pub fn generate_response_with_history(
+    messages_json: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateHistoryTask>> {
+    let task = GenerateHistoryTask {
+        messages_json,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+/// `generate_deterministic` for a conversation history, taking the same
+/// `messages_json` as `generate_response_with_history`.
+#[napi(ts_return_type = "Promise<string> & { requestId: number }")]
+pub fn generate_deterministic_with_history(
+    messages_json: String,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateHistoryTask>> {
+    let task = GenerateHistoryTask {
+        messages_json,
+        options: NativeOptions::greedy(max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+/// Like `generate_response_with_history`, but takes the messages as objects
+/// instead of a JSON string.
+#[napi(ts_return_type = "Promise<string> & { requestId: number }")]
+pub fn generate_response_messages(
+    messages: Vec<ChatMessage>,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateHistoryTask>> {
+    validate_messages(&messages)?;
+    let messages_json = serde_json::to_string(&messages)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to encode messages: {e}")))?;
+    generate_response_with_history(messages_json, temperature, max_tokens, options)
+}
+
+// ---------------- Generation with usage ----------------
+
+/// Generated text plus token usage and timing.
+///
+/// FoundationModels doesn't report usage, so the counts are estimates made by
+/// the Swift layer. They are still reported when `max_tokens` cut the
+/// response short.
+#[napi(object)]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationResult {
+    pub text: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// Wall-clock time spent generating, in milliseconds.
+    #[serde(default)]
+    pub latency_ms: u32,
+    /// `completion_tokens` over the generation time.
+    #[serde(default)]
+    pub tokens_per_second: f64,
+    /// Whether the response was produced on this device rather than by
+    /// Private Cloud Compute. Only `true` when the Swift layer confirms it.
+    #[serde(default)]
+    pub on_device: bool,
+    /// Tool the model asked to call, from `generate_with_tools`; `text` is
+    /// empty then.
+    #[serde(default)]
+    pub tool_call: Option<ToolCall>,
+    /// Why generation ended: `stop` for a natural end, `length` when the
+    /// output reached `max_tokens` (judged by the estimated count) or was cut
+    /// at `max_chars`, or `stop_sequence`. Failed generations reject instead.
+    #[napi(ts_type = "'stop' | 'length' | 'stop_sequence'")]
+    #[serde(default)]
+    pub finish_reason: String,
+}
+
+/// `length` if `completion_tokens` (estimated) reached a `max_tokens` limit,
+/// otherwise `stop`.
+fn natural_finish_reason(completion_tokens: u32, max_tokens: i32) -> &'static str {
+    match u32::try_from(max_tokens) {
+        Ok(limit) if limit > 0 && completion_tokens >= limit => "length",
+        _ => "stop",
+    }
+}
+
+/// Shorten `text` to its first `max_chars` characters; whether it was longer.
+fn truncate_chars(text: &mut String, max_chars: usize) -> bool {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => {
+            text.truncate(end);
+            true
+        }
+        None => false,
+    }
+}
+
+fn tokens_per_second(tokens: u32, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        f64::from(tokens) / secs
+    } else {
+        0.0
+    }
+}
+
+/// Rust twin of the Swift layer's `estimateTokenCount`: ~4 ASCII characters
+/// per token, one token per other character.
+fn estimate_tokens(text: &str) -> u32 {
+    let ascii = text.chars().filter(char::is_ascii).count();
+    estimate_tokens_from_counts(text.chars().count(), ascii)
+}
+
+/// `estimate_tokens` for a text of `chars` characters, `ascii` of them ASCII.
+fn estimate_tokens_from_counts(chars: usize, ascii: usize) -> u32 {
+    if chars == 0 {
+        return 0;
+    }
+    u32::try_from(ascii.div_ceil(4) + (chars - ascii))
+        .unwrap_or(u32::MAX)
+        .max(1)
+}
+
+/// Decode the JSON returned by the `_v2` entry points: a result, or an error
+/// payload whose code is recorded on `handle`. Anything else is a plain
+/// `Error: …` string.
+fn parse_generation_result(raw: String, handle: &RequestHandle) -> napi::Result<GenerationResult> {
+    if let Ok(result) = serde_json::from_str(&raw) {
+        return Ok(result);
+    }
+    if let Ok(error) = serde_json::from_str::<NativeError>(&raw) {
+        let _ = handle
+            .error_code
+            .set(GenerationErrorCode::from_code(error.code));
+        return Err(napi::Error::from_reason(error.message));
+    }
+    Err(napi::Error::from_reason(
+        raw.strip_prefix("Error: ").unwrap_or(&raw).to_string(),
+    ))
+}
+
+pub struct GenerateWithUsageTask {
+    pub prompt: String,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for GenerateWithUsageTask {
+    type Output = GenerationResult;
+    type JsValue = GenerationResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        let c_prompt = self
+            .options
+            .c_text(std::mem::take(&mut self.prompt), "Prompt")?;
+        if self.options.check_context_window {
+            let prompt_tokens = unsafe { apple_ai_count_tokens(c_prompt.as_ptr()) };
+            check_context_window(prompt_tokens, &self.handle)?;
+        }
+        run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_generate_with_instructions(c"".as_ptr(), c_prompt.as_ptr(), options, handle)
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for GenerateWithUsageTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Like `generate_response`, but resolves to a `GenerationResult` with usage.
+#[napi(ts_return_type = "Promise<GenerationResult> & { requestId: number }")]
+pub fn generate_response_with_usage(
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateWithUsageTask>> {
+    let task = GenerateWithUsageTask {
+        prompt,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+pub struct GenerateHistoryWithUsageTask {
+    pub messages_json: String,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for GenerateHistoryWithUsageTask {
+    type Output = GenerationResult;
+    type JsValue = GenerationResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        let c_json = validated_messages_json(&self.messages_json)?;
+        if self.options.check_context_window {
+            let prompt_tokens = unsafe { apple_ai_count_tokens_for_messages(c_json.as_ptr()) };
+            check_context_window(prompt_tokens, &self.handle)?;
+        }
+        run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_generate_response_with_history_v2(c_json.as_ptr(), options, handle)
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for GenerateHistoryWithUsageTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Like `generate_response_with_history`, but resolves to a
+/// `GenerationResult` with usage.
+#[napi(ts_return_type = "Promise<GenerationResult> & { requestId: number }")]
+pub fn generate_response_with_history_and_usage(
+    messages_json: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateHistoryWithUsageTask>> {
+    let task = GenerateHistoryWithUsageTask {
+        messages_json,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+/// Generate the next assistant turn after `turns` (e.g. few-shot examples
+/// followed by the real question), resolving to a `GenerationResult` with
+/// usage and finish reason.
+///
+/// Roles and content are validated up front, and the last turn must be a
+/// `user` one, since it is what the model answers.
+#[napi(ts_return_type = "Promise<GenerationResult> & { requestId: number }")]
+pub fn generate_response_chat(
+    turns: Vec<ChatMessage>,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateHistoryWithUsageTask>> {
+    validate_messages(&turns)?;
+    if turns.last().is_some_and(|turn| turn.role != "user") {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "The last turn must have role user".to_string(),
+        ));
+    }
+    let messages_json = serde_json::to_string(&turns)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to encode messages: {e}")))?;
+    generate_response_with_history_and_usage(messages_json, temperature, max_tokens, options)
+}
+
+/// A reply as a conversation turn, ready to push onto the history for the
+/// next call. Sending `usage` back with it is harmless: it's ignored.
+#[napi(object)]
+pub struct AssistantMessage {
+    #[napi(ts_type = "'assistant'")]
+    pub role: String,
+    pub content: String,
+    pub usage: MessageUsage,
+}
+
+/// Estimated like `GenerationResult`'s counts.
+#[napi(object)]
+pub struct MessageUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+pub struct GenerateHistoryMessageTask(GenerateHistoryWithUsageTask);
+
+impl napi::Task for GenerateHistoryMessageTask {
+    type Output = GenerationResult;
+    type JsValue = AssistantMessage;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        self.0.compute()
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(AssistantMessage {
+            role: "assistant".to_string(),
+            content: output.text,
+            usage: MessageUsage {
+                prompt_tokens: output.prompt_tokens,
+                completion_tokens: output.completion_tokens,
+            },
+        })
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.0.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for GenerateHistoryMessageTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.0.handle
+    }
+}
+
+/// Like `generate_response_with_history`, but resolves to the reply as an
+/// assistant message.
+#[napi(ts_return_type = "Promise<AssistantMessage> & { requestId: number }")]
+pub fn generate_response_with_history_message(
+    messages_json: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateHistoryMessageTask>> {
+    let task = GenerateHistoryWithUsageTask {
+        messages_json,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(GenerateHistoryMessageTask(task)))
+}
+
+// ---------------- Batch generation ----------------
+
+/// Outcome of one prompt of `generate_batch`: `text` on success, otherwise
+/// `error` and `code`, or `cancelled` if the batch was aborted first.
+#[napi(object)]
+pub struct BatchResult {
+    pub text: Option<String>,
+    pub error: Option<String>,
+    pub code: Option<GenerationErrorCode>,
+    /// The batch's signal aborted before or while this prompt ran.
+    pub cancelled: bool,
+}
+
+/// Abort state of a `generate_batch`, shared with its `AbortSignal` listener.
+#[derive(Default)]
+pub struct BatchAbort {
+    aborted: AtomicBool,
+    /// Handle of the prompt being generated
+    current: Mutex<Option<Arc<RequestHandle>>>,
+}
+
+impl BatchAbort {
+    fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        if let Some(handle) = lock(&self.current).as_ref() {
+            handle.cancel();
+        }
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+}
+
+pub struct BatchTask {
+    pub prompts: Vec<String>,
+    pub options: NativeOptions,
+    pub abort: Arc<BatchAbort>,
+}
+
+impl napi::Task for BatchTask {
+    type Output = Vec<BatchResult>;
+    type JsValue = Vec<BatchResult>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        let results = std::mem::take(&mut self.prompts)
+            .into_iter()
+            .map(|prompt| {
+                let handle = RequestHandle::new();
+                *lock(&self.abort.current) = Some(Arc::clone(&handle));
+                if self.abort.is_aborted() {
+                    return BatchResult {
+                        text: None,
+                        error: None,
+                        code: None,
+                        cancelled: true,
+                    };
+                }
+                let result = self.options.c_text(prompt, "Prompt").and_then(|c_prompt| {
+                    if self.options.check_context_window {
+                        let prompt_tokens = unsafe { apple_ai_count_tokens(c_prompt.as_ptr()) };
+                        check_context_window(prompt_tokens, &handle)?;
+                    }
+                    run_generation(&self.options, &handle, |options, handle| unsafe {
+                        apple_ai_generate_with_instructions(
+                            c"".as_ptr(),
+                            c_prompt.as_ptr(),
+                            options,
+                            handle,
+                        )
+                    })
+                });
+                match result {
+                    Ok(result) => BatchResult {
+                        text: Some(result.text),
+                        error: None,
+                        code: None,
+                        cancelled: false,
+                    },
+                    Err(err) => BatchResult {
+                        code: Some(handle.error_code(&err)),
+                        error: Some(err.reason),
+                        text: None,
+                        cancelled: handle.is_cancelled(),
+                    },
+                }
+            })
+            .collect();
+        lock(&self.abort.current).take();
+        Ok(results)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// Generate a response for each of `prompts` in a single background task.
+///
+/// Prompts run one after another with the same settings; results are in
+/// input order. A failing prompt is reported in its `BatchResult` and doesn't
+/// stop the others. Rejects only if the model can't be used at all.
+///
+/// Aborting `signal` stops the batch without rejecting: the running prompt
+/// is cancelled, prompts not yet started are skipped, and both are marked
+/// `cancelled` among the results.
+#[napi]
+pub fn generate_batch(
+    env: Env,
+    prompts: Vec<String>,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+    #[napi(ts_arg_type = "AbortSignal | undefined")] signal: Option<JsObject>,
+) -> napi::Result<AsyncTask<BatchTask>> {
+    let task = BatchTask {
+        prompts,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        abort: Arc::default(),
+    };
+    if let Some(signal) = signal {
+        let abort = Arc::clone(&task.abort);
+        on_abort(&env, &signal, move || abort.abort())?;
+    }
+    Ok(AsyncTask::new(task))
+}
+
+// Safe global stream registry -----------------------------------------------
+
+/// One event delivered to a stream callback.
+///
+/// Every stream ends with exactly one event that has `done` set; if the
+/// stream failed, that event carries `error` instead of more text.
+#[napi(object)]
+pub struct StreamChunk {
+    /// `prompt` for the `echo_prompt` event, which carries the prompt in
+    /// `text` and doesn't count towards the output; `tool_call` for a
+    /// `generate_with_tools_stream` event carrying a `tool_call`; `text` for
+    /// all others.
+    #[napi(ts_type = "'prompt' | 'text' | 'tool_call'")]
+    pub kind: String,
+    /// The newly generated text.
+    pub delta: String,
+    /// Everything generated, on the final event (and the `prompt` event's
+    /// prompt). Empty on the others, rather than copying the whole text into
+    /// every event: join the `delta`s for a running copy.
+    pub text: String,
+    /// Tokens generated so far. The Swift layer doesn't report per-chunk
+    /// counts, so this is estimated like `GenerationResult.completion_tokens`.
+    pub tokens_so_far: u32,
+    pub done: bool,
+    /// Set on the final event when the stream was stopped by `cancel_stream`.
+    pub cancelled: bool,
+    pub error: Option<String>,
+    /// Set together with `error`.
+    pub code: Option<GenerationErrorCode>,
+    /// Timing of the whole stream, on the final event of a successful stream.
+    pub metrics: Option<StreamMetrics>,
+    /// For `generate_structured_stream`: `text` with any unterminated string,
+    /// array or object closed so it parses as JSON, when that is possible.
+    /// On the final event, the complete object.
+    pub partial_object: Option<String>,
+    /// On the final event: like `GenerationResult.finish_reason`, or
+    /// `cancelled` when stopped by `cancel_stream`, or `guardrail` when the
+    /// stream failed on Apple's safety guardrails. Absent for other errors.
+    #[napi(ts_type = "'stop' | 'length' | 'stop_sequence' | 'guardrail' | 'cancelled'")]
+    pub finish_reason: Option<String>,
+    /// `GenerationOptions.metadata` of the stream, on every event.
+    pub metadata: Option<HashMap<String, String>>,
+    /// With the `as_buffer` option: `delta` as UTF-8 bytes, ending on a
+    /// character boundary, while `delta` itself is left empty.
+    pub buffer: Option<Buffer>,
+    /// With the `stream_timings` option, wherever `metrics` is set.
+    pub timings: Option<StreamTimings>,
+    /// On `tool_call` events: the call the model is waiting on, to fulfil
+    /// with `submit_tool_result`.
+    pub tool_call: Option<ToolCall>,
+}
+
+#[napi(object)]
+pub struct StreamMetrics {
+    /// From starting the stream to its first non-empty chunk; absent if
+    /// nothing was generated.
+    pub time_to_first_token_ms: Option<u32>,
+    /// From starting the stream to its end.
+    pub latency_ms: u32,
+    /// Estimated like `GenerationResult.completion_tokens`.
+    pub completion_tokens: u32,
+    pub tokens_per_second: f64,
+    /// Like `GenerationResult.on_device`.
+    pub on_device: bool,
+}
+
+/// Where a stream's time went, to tell model load and prompt processing
+/// (before the first chunk) from steady-state generation. The library's own
+/// initialization isn't part of any stream: it happens once, on first use or
+/// in `initialize`.
+#[napi(object)]
+pub struct StreamTimings {
+    /// From starting the stream to the first chunk from the model, which
+    /// includes loading it unless prewarmed; absent if there was none.
+    pub time_to_first_chunk_ms: Option<f64>,
+    /// Time between each chunk and the one before it, in order.
+    pub chunk_gaps_ms: Vec<f64>,
+    pub max_chunk_gap_ms: f64,
+    pub mean_chunk_gap_ms: f64,
+    /// From starting the stream to its end.
+    pub total_ms: f64,
+    /// Chunks that carried text; end markers and the prompt echo don't count.
+    pub chunk_count: u32,
+}
+
+impl StreamChunk {
+    fn text(delta: String, text: String) -> Self {
+        StreamChunk {
+            kind: "text".to_string(),
+            delta,
+            tokens_so_far: estimate_tokens(&text),
+            text,
+            done: false,
+            cancelled: false,
+            error: None,
+            code: None,
+            metrics: None,
+            partial_object: None,
+            finish_reason: None,
+            metadata: None,
+            buffer: None,
+            timings: None,
+            tool_call: None,
+        }
+    }
+
+    fn end(delta: String, text: String) -> Self {
+        StreamChunk {
+            kind: "text".to_string(),
+            delta,
+            tokens_so_far: estimate_tokens(&text),
+            text,
+            done: true,
+            cancelled: false,
+            error: None,
+            code: None,
+            metrics: None,
+            partial_object: None,
+            finish_reason: None,
+            metadata: None,
+            buffer: None,
+            timings: None,
+            tool_call: None,
+        }
+    }
+
+    fn error(code: GenerationErrorCode, message: String) -> Self {
+        StreamChunk {
+            kind: "text".to_string(),
+            delta: String::new(),
+            text: String::new(),
+            tokens_so_far: 0,
+            done: true,
+            cancelled: false,
+            error: Some(message),
+            code: Some(code),
+            metrics: None,
+            partial_object: None,
+            finish_reason: None,
+            metadata: None,
+            buffer: None,
+            timings: None,
+            tool_call: None,
+        }
+    }
+}
+
+/// Where a stream's events go.
+#[derive(Clone)]
+enum StreamSink {
+    /// Pushed to a JS callback as they arrive, through a queue that is
+    /// bounded if there's a `StreamBuffer`.
+    Callback(
+        ThreadsafeFunction<StreamChunk, ErrorStrategy::Fatal>,
+        Option<Arc<StreamBuffer>>,
+    ),
+    /// Queued for a `StreamReader`; a full queue blocks the producer.
+    Channel(mpsc::SyncSender<StreamChunk>),
+}
+
+impl StreamSink {
+    /// Deliver `chunk` without ever blocking, for the JS thread: if a
+    /// reader's queue is full, the chunk is dropped and the reader just sees
+    /// the end of the stream once the state (and sender) is gone.
+    fn send(&self, chunk: StreamChunk) {
+        match self {
+            StreamSink::Callback(tsfn, buffer) => {
+                if let Some(buffer) = buffer {
+                    buffer.push();
+                }
+                let status = tsfn.call(chunk, ThreadsafeFunctionCallMode::NonBlocking);
+                if let (Some(buffer), false) = (buffer, status == Status::Ok) {
+                    buffer.pop();
+                }
+            }
+            StreamSink::Channel(tx) => {
+                let _ = tx.try_send(chunk);
+            }
+        }
+    }
+
+    /// Whether `send_from_producer` may block.
+    fn is_bounded(&self) -> bool {
+        matches!(
+            self,
+            StreamSink::Channel(_) | StreamSink::Callback(_, Some(_))
+        )
+    }
+
+    /// `send` for chunks from Swift and the timeout watchers, which waits
+    /// while the queue is full so generation pauses for a slow consumer.
+    /// Events raised on the JS thread (cancellation, reaping) use `send` and
+    /// are never held back.
+    fn send_from_producer(&self, chunk: StreamChunk) {
+        match self {
+            StreamSink::Callback(_, Some(buffer)) => {
+                buffer.wait_for_room();
+                self.send(chunk);
+            }
+            StreamSink::Channel(tx) => {
+                let _ = tx.send(chunk);
+            }
+            StreamSink::Callback(_, None) => self.send(chunk),
+        }
+    }
+}
+
+/// Count of the chunks a bounded callback stream has queued for JS.
+struct StreamBuffer {
+    capacity: usize,
+    queued: Mutex<usize>,
+    drained: Condvar,
+}
+
+impl StreamBuffer {
+    fn wait_for_room(&self) {
+        let mut queued = lock(&self.queued);
+        while *queued >= self.capacity {
+            queued = wait(&self.drained, queued);
+        }
+    }
+
+    fn push(&self) {
+        *lock(&self.queued) += 1;
+    }
+
+    fn pop(&self) {
+        let mut queued = lock(&self.queued);
+        *queued = queued.saturating_sub(1);
+        self.drained.notify_all();
+    }
+}
+
+struct StreamState {
+    /// The stream id, which is also the request id
+    id: u32,
+    sink: StreamSink,
+    prompt: CString, // keeps the CString alive for the duration of the stream
+    /// Trailing bytes of an incomplete UTF-8 sequence, completed by the next chunk
+    pending: Vec<u8>,
+    /// Everything delivered so far
+    text: String,
+    /// Characters in `text`, and how many are ASCII, kept up to date rather
+    /// than recounted per chunk
+    text_chars: usize,
+    text_ascii: usize,
+    started: Instant,
+    first_chunk_at: Option<Instant>,
+    /// When Swift last delivered anything, for the idle timeout
+    last_chunk_at: Instant,
+    /// The text is a JSON object, reported in `partial_object`
+    structured: bool,
+    /// Deltas go out as `buffer`
+    as_buffer: bool,
+    max_chars: Option<u32>,
+    /// Session whose token budget the stream is charged to when it ends
+    budget_session: Option<u32>,
+    /// Arrival of every text chunk from Swift, with the `stream_timings` option
+    chunk_times: Option<Vec<Instant>>,
+    max_tokens: i32,
+    /// Reported by Swift before the end marker, if not a natural end
+    finish_reason: Option<String>,
+    metadata: Option<HashMap<String, String>>,
+    /// Tool calls reported but not yet answered by `submit_tool_result`
+    pending_tool_calls: u32,
+    /// Set while Swift waits for room in a full consumer queue
+    producer_waiting: Arc<AtomicBool>,
+    /// Held for the timeout watchers: dropping the state with the stream
+    /// wakes them, so they exit as soon as it ends
+    watchers: Vec<mpsc::Sender<()>>,
+}
+
+impl StreamState {
+    /// Event for a newly decoded `delta`, appending it to the text so far.
+    fn text_chunk(&mut self, delta: String, done: bool) -> StreamChunk {
+        if !delta.is_empty() {
+            self.first_chunk_at.get_or_insert_with(Instant::now);
+            // Only text counts as a chunk: markers and echoes aren't timed
+            if let Some(times) = &mut self.chunk_times {
+                times.push(self.last_chunk_at);
+            }
+            self.text.push_str(&delta);
+            self.text_chars += delta.chars().count();
+            self.text_ascii += delta.chars().filter(char::is_ascii).count();
+        }
+        let chunk = self.text_event(delta, done);
+        if self.as_buffer && chunk.error.is_none() {
+            StreamChunk {
+                buffer: Some(chunk.delta.into_bytes().into()),
+                delta: String::new(),
+                ..chunk
+            }
+        } else {
+            chunk
+        }
+    }
+
+    fn text_event(&mut self, delta: String, done: bool) -> StreamChunk {
+        if done {
+            let partial_object = if self.structured {
+                if let Err(e) = serde_json::from_str::<serde_json::Value>(&self.text) {
+                    return self.error_chunk(
+                        GenerationErrorCode::MalformedJson,
+                        format!("Response is not valid JSON: {e}"),
+                    );
+                }
+                Some(self.text.clone())
+            } else {
+                None
+            };
+            let finish_reason = self.finish_reason.take().unwrap_or_else(|| {
+                natural_finish_reason(estimate_tokens(&self.text), self.max_tokens).to_string()
+            });
+            StreamChunk {
+                metrics: Some(self.metrics()),
+                timings: self.timings(),
+                partial_object,
+                finish_reason: Some(finish_reason),
+                metadata: self.metadata.clone(),
+                ..StreamChunk::end(delta, self.text.clone())
+            }
+        } else {
+            StreamChunk {
+                partial_object: self
+                    .structured
+                    .then(|| close_partial_json(&self.text))
+                    .flatten(),
+                metadata: self.metadata.clone(),
+                tokens_so_far: self.tokens_so_far(),
+                ..StreamChunk::text(delta, String::new())
+            }
+        }
+    }
+
+    /// Shorten `delta` to what still fits under `max_chars`; whether the
+    /// stream reached the cap.
+    fn cut_at_max_chars(&self, delta: &mut String) -> bool {
+        let Some(max_chars) = self.max_chars else {
+            return false;
+        };
+        let remaining = (max_chars as usize).saturating_sub(self.text_chars);
+        truncate_chars(delta, remaining) || delta.chars().count() == remaining
+    }
+
+    fn timings(&self) -> Option<StreamTimings> {
+        let times = self.chunk_times.as_ref()?;
+        let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+        let chunk_gaps_ms: Vec<f64> = times.windows(2).map(|w| millis(w[1] - w[0])).collect();
+        let mean_chunk_gap_ms = if chunk_gaps_ms.is_empty() {
+            0.0
+        } else {
+            chunk_gaps_ms.iter().sum::<f64>() / chunk_gaps_ms.len() as f64
+        };
+        Some(StreamTimings {
+            time_to_first_chunk_ms: times.first().map(|&t| millis(t - self.started)),
+            max_chunk_gap_ms: chunk_gaps_ms.iter().copied().fold(0.0, f64::max),
+            mean_chunk_gap_ms,
+            chunk_gaps_ms,
+            total_ms: millis(self.started.elapsed()),
+            chunk_count: times.len() as u32,
+        })
+    }
+
+    /// Charge the stream's prompt and output to its session's token budget.
+    fn charge_budget(&self) {
+        if let Some(session_id) = self.budget_session {
+            let prompt_tokens = estimate_tokens(&self.prompt.to_string_lossy());
+            charge_session_budget(
+                session_id,
+                prompt_tokens.saturating_add(estimate_tokens(&self.text)),
+            );
+        }
+    }
+
+    /// Event for a tool call the stream is now waiting on.
+    fn tool_call_chunk(&self, call: ToolCall) -> StreamChunk {
+        StreamChunk {
+            kind: "tool_call".to_string(),
+            metadata: self.metadata.clone(),
+            tool_call: Some(call),
+            tokens_so_far: self.tokens_so_far(),
+            ..StreamChunk::text(String::new(), String::new())
+        }
+    }
+
+    fn tokens_so_far(&self) -> u32 {
+        estimate_tokens_from_counts(self.text_chars, self.text_ascii)
+    }
+
+    /// Final event of a failed stream.
+    fn error_chunk(&self, code: GenerationErrorCode, message: String) -> StreamChunk {
+        log_request_event("error", self.id, self.metadata.as_ref(), || {
+            format!("Stream {} failed: {message}", self.id)
+        });
+        StreamChunk {
+            metadata: self.metadata.clone(),
+            text: self.text.clone(),
+            tokens_so_far: estimate_tokens(&self.text),
+            finish_reason: (code == GenerationErrorCode::GuardrailViolation)
+                .then(|| "guardrail".to_string()),
+            ..StreamChunk::error(code, message)
+        }
+    }
+
+    fn metrics(&self) -> StreamMetrics {
+        let elapsed = self.started.elapsed();
+        let completion_tokens = estimate_tokens(&self.text);
+        let millis = |d: Duration| d.as_millis().try_into().unwrap_or(u32::MAX);
+        StreamMetrics {
+            time_to_first_token_ms: self.first_chunk_at.map(|t| millis(t - self.started)),
+            latency_ms: millis(elapsed),
+            completion_tokens,
+            tokens_per_second: tokens_per_second(completion_tokens, elapsed),
+            on_device: unsafe { apple_ai_last_generation_on_device() },
+        }
+    }
+}
+
+static STREAMS: OnceLock<Mutex<HashMap<u32, StreamState>>> = OnceLock::new();
+
+/// Lock the stream registry with `lock`: at worst the stream being handled
+/// is left half-updated, and the others keep working.
+fn lock_streams() -> MutexGuard<'static, HashMap<u32, StreamState>> {
+    lock(STREAMS.get_or_init(|| Mutex::new(HashMap::new())))
+}
+
+const ERROR_SENTINEL: u8 = 0x02;
+/// Prefix of a chunk naming why the stream is about to end
+const FINISH_SENTINEL: u8 = 0x03;
+/// Prefix of a chunk carrying a tool call as JSON `{name, arguments}`
+const TOOL_CALL_SENTINEL: u8 = 0x04;
+/// Prefix of the chunk carrying the final prompt, for `echo_prompt`
+const PROMPT_SENTINEL: u8 = 0x05;
+/// Chunk voiding the text so far, which the following chunks restart: a
+/// structured stream's snapshot may not extend the one before it
+const RESTART_SENTINEL: u8 = 0x06;
+
+/// Decode the complete UTF-8 prefix of `buf`, leaving a trailing incomplete
+/// sequence in it for the next chunk. Invalid bytes become U+FFFD.
+fn drain_utf8(buf: &mut Vec<u8>) -> String {
+    let mut out = String::new();
+    loop {
+        match std::str::from_utf8(buf) {
+            Ok(text) => {
+                out.push_str(text);
+                buf.clear();
+                return out;
+            }
+            Err(e) => {
+                let valid = e.valid_up_to();
+                out.push_str(std::str::from_utf8(&buf[..valid]).unwrap_or_default());
+                match e.error_len() {
+                    // Incomplete sequence at the end: wait for more bytes
+                    None => {
+                        buf.drain(..valid);
+                        return out;
+                    }
+                    Some(len) => {
+                        out.push(char::REPLACEMENT_CHARACTER);
+                        buf.drain(..valid + len);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decode whatever `drain_utf8` left in `buf` once no more bytes will come;
+/// a dangling incomplete sequence becomes U+FFFD.
+fn flush_utf8(buf: &mut Vec<u8>) -> String {
+    let rest = String::from_utf8_lossy(buf).into_owned();
+    buf.clear();
+    rest
+}
+
+// Called from Swift threads, so a panic must not unwind out of it. This
+// only matters in unwinding builds: the release profile sets
+// `panic = "abort"`, where any panic ends the process before getting here
+extern "C" fn chunk_callback(stream_id: u32, ptr: *const c_char) {
+    if panic::catch_unwind(|| handle_chunk(stream_id, ptr)).is_err() {
+        log_event("error", || {
+            format!("Stream {stream_id} chunk handler panicked")
+        });
+    }
+}
+
+// Finished streams are removed from the registry and their threadsafe
+// function dropped (not aborted), so already queued events still reach JS.
+fn handle_chunk(stream_id: u32, ptr: *const c_char) {
+    let mut guard = lock_streams();
+
+    let Some(state) = guard.get_mut(&stream_id) else {
+        // Cancelled or unknown stream: just release the chunk
+        take_c_string(ptr as *mut c_char);
+        return;
+    };
+    state.last_chunk_at = Instant::now();
+
+    let (chunk, finished) = if ptr.is_null() {
+        // End of stream: flush what's left of a truncated sequence with the final event
+        let rest = flush_utf8(&mut state.pending);
+        (state.text_chunk(rest, true), true)
+    } else {
+        // Take ownership and free C string once here
+        let bytes = take_c_bytes(ptr as *mut c_char);
+        if bytes.is_empty() {
+            return;
+        }
+
+        if bytes[0] == ERROR_SENTINEL {
+            let payload = String::from_utf8_lossy(&bytes[1..]).into_owned();
+            let chunk = match serde_json::from_str::<NativeError>(&payload) {
+                Ok(error) => {
+                    state.error_chunk(GenerationErrorCode::from_code(error.code), error.message)
+                }
+                Err(_) => state.error_chunk(GenerationErrorCode::Unknown, payload),
+            };
+            // An error ends the stream; Swift sends no end marker after it
+            (chunk, true)
+        } else if bytes[0] == FINISH_SENTINEL {
+            state.finish_reason = Some(String::from_utf8_lossy(&bytes[1..]).into_owned());
+            return;
+        } else if bytes[0] == RESTART_SENTINEL {
+            state.text.clear();
+            state.text_chars = 0;
+            state.text_ascii = 0;
+            state.pending.clear();
+            return;
+        } else if bytes[0] == PROMPT_SENTINEL {
+            let prompt = String::from_utf8_lossy(&bytes[1..]).into_owned();
+            let chunk = StreamChunk {
+                kind: "prompt".to_string(),
+                metadata: state.metadata.clone(),
+                ..StreamChunk::text(String::new(), prompt)
+            };
+            (chunk, false)
+        } else if bytes[0] == TOOL_CALL_SENTINEL {
+            match serde_json::from_slice::<ToolCall>(&bytes[1..]) {
+                Ok(call) => {
+                    state.pending_tool_calls += 1;
+                    (state.tool_call_chunk(call), false)
+                }
+                Err(e) => {
+                    unsafe { apple_ai_cancel_stream(stream_id) };
+                    let message = format!("Malformed tool call: {e}");
+                    (
+                        state.error_chunk(GenerationErrorCode::Unknown, message),
+                        true,
+                    )
+                }
+            }
+        } else {
+            state.pending.extend_from_slice(&bytes);
+            let mut text = drain_utf8(&mut state.pending);
+            if text.is_empty() {
+                return;
+            }
+            if state.cut_at_max_chars(&mut text) {
+                // Nothing past the cap is wanted, so stop generating
+                unsafe { apple_ai_cancel_stream(stream_id) };
+                state.finish_reason = Some("length".to_string());
+                (state.text_chunk(text, true), true)
+            } else {
+                (state.text_chunk(text, false), false)
+            }
+        }
+    };
+
+    let sink = state.sink.clone();
+    let producer_waiting = Arc::clone(&state.producer_waiting);
+    if finished {
+        finish_stream(&mut guard, stream_id);
+    }
+    if sink.is_bounded() {
+        // Blocks this (Swift) thread while the consumer's queue is full, so
+        // don't hold the registry lock meanwhile
+        producer_waiting.store(true, Ordering::Release);
+        drop(guard);
+    }
+    sink.send_from_producer(chunk);
+    producer_waiting.store(false, Ordering::Release);
+}
+
+/// Take stream `stream_id` out of the registry, charging what it generated
+/// to its session's budget. Every way a stream can end goes through here.
+fn finish_stream(streams: &mut HashMap<u32, StreamState>, stream_id: u32) -> Option<StreamState> {
+    let state = streams.remove(&stream_id)?;
+    state.charge_budget();
+    Some(state)
+}
+
+/// Register a new stream delivering chunks to `callback` and return its id.
+fn register_stream(
+    callback: JsFunction,
+    prompt: &CString,
+    options: &NativeOptions,
+) -> napi::Result<u32> {
+    let buffer = options.stream_buffer_size.map(|capacity| {
+        Arc::new(StreamBuffer {
+            capacity: capacity as usize,
+            queued: Mutex::new(0),
+            drained: Condvar::new(),
+        })
+    });
+    let dequeued = buffer.clone();
+    let ts_fn: ThreadsafeFunction<StreamChunk, ErrorStrategy::Fatal> = callback
+        .create_threadsafe_function(0, move |ctx: ThreadSafeCallContext<StreamChunk>| {
+            if let Some(buffer) = &dequeued {
+                buffer.pop();
+            }
+            Ok(vec![ctx.value])
+        })?;
+    Ok(register_sink(
+        StreamSink::Callback(ts_fn, buffer),
+        prompt,
+        options,
+    ))
+}
+
+/// A stream that hasn't heard from Swift for this long is assumed to have
+/// lost its end marker (e.g. the native side failed mid-stream), unless it
+/// is waiting on a tool result or on its consumer.
+const STALE_STREAM_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// End and drop streams that went stale, so they don't leak forever.
+fn reap_stale_streams() {
+    let stale: Vec<(u32, StreamState)> = {
+        let mut streams = lock_streams();
+        let ids: Vec<u32> = streams
+            .iter()
+            .filter(|(_, state)| {
+                state.last_chunk_at.elapsed() >= STALE_STREAM_AFTER
+                    && state.pending_tool_calls == 0
+                    && !state.producer_waiting.load(Ordering::Acquire)
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| finish_stream(&mut streams, id).map(|state| (id, state)))
+            .collect()
+    };
+    for (stream_id, state) in stale {
+        log_request_event("warn", stream_id, state.metadata.as_ref(), || {
+            format!("Stream {stream_id} never ended; cleaning it up")
+        });
+        unsafe { apple_ai_cancel_stream(stream_id) };
+        state.sink.send(state.error_chunk(
+            GenerationErrorCode::Unknown,
+            "Stream ended without finishing".to_string(),
+        ));
+    }
+}
+
+/// Whether any stream is still running.
+#[napi]
+pub fn is_streaming() -> bool {
+    !lock_streams().is_empty()
+}
+
+/// Ids of the streams still running, in ascending order.
+#[napi]
+pub fn active_streams() -> Vec<u32> {
+    let mut ids: Vec<u32> = lock_streams().keys().copied().collect();
+    ids.sort_unstable();
+    ids
+}
+
+fn register_sink(sink: StreamSink, prompt: &CString, options: &NativeOptions) -> u32 {
+    reap_stale_streams();
+    let stream_id = next_request_id();
+    lock_streams().insert(
+        stream_id,
+        StreamState {
+            id: stream_id,
+            sink,
+            prompt: prompt.clone(),
+            pending: Vec::new(),
+            text: String::new(),
+            text_chars: 0,
+            text_ascii: 0,
+            started: Instant::now(),
+            first_chunk_at: None,
+            last_chunk_at: Instant::now(),
+            structured: false,
+            as_buffer: options.as_buffer,
+            max_chars: options.max_chars,
+            budget_session: None,
+            chunk_times: options.stream_timings.then(Vec::new),
+            max_tokens: options.max_tokens,
+            finish_reason: None,
+            metadata: options.metadata.clone(),
+            pending_tool_calls: 0,
+            producer_waiting: Arc::new(AtomicBool::new(false)),
+            watchers: Vec::new(),
+        },
+    );
+    stream_id
+}
+
+/// End stream `stream_id` with a timeout error unless it finishes within `timeout_ms`.
+fn watch_stream_timeout(stream_id: u32, options: &NativeOptions) {
+    if let Some(timeout_ms) = options.timeout_ms {
+        let ended = stream_ended(stream_id);
+        thread::spawn(move || {
+            let timeout = Duration::from_millis(timeout_ms.into());
+            if ended.recv_timeout(timeout) != Err(mpsc::RecvTimeoutError::Timeout) {
+                return;
+            }
+            let state = finish_stream(&mut lock_streams(), stream_id);
+            if let Some(state) = state {
+                unsafe { apple_ai_cancel_stream(stream_id) };
+                state.sink.send_from_producer(state.error_chunk(
+                    GenerationErrorCode::Timeout,
+                    timeout_error(timeout_ms).reason,
+                ));
+            }
+        });
+    }
+    if let Some(idle_timeout_ms) = options.idle_timeout_ms {
+        let ended = stream_ended(stream_id);
+        thread::spawn(move || watch_stream_idle(stream_id, idle_timeout_ms, ended));
+    }
+}
+
+/// A receiver that disconnects once stream `stream_id` has ended (right
+/// away if it already has), like the one `start_watchdog` waits on.
+fn stream_ended(stream_id: u32) -> mpsc::Receiver<()> {
+    let (ended_tx, ended_rx) = mpsc::channel();
+    if let Some(state) = lock_streams().get_mut(&stream_id) {
+        state.watchers.push(ended_tx);
+    }
+    ended_rx
+}
+
+/// End stream `stream_id` with a timeout error once no chunk has arrived for
+/// `idle_timeout_ms`; every chunk restarts the wait.
+fn watch_stream_idle(stream_id: u32, idle_timeout_ms: u32, ended: mpsc::Receiver<()>) {
+    let idle_timeout = Duration::from_millis(idle_timeout_ms.into());
+    let mut wait = idle_timeout;
+    loop {
+        if ended.recv_timeout(wait) != Err(mpsc::RecvTimeoutError::Timeout) {
+            return;
+        }
+        let mut streams = lock_streams();
+        let Some(state) = streams.get(&stream_id) else {
+            return;
+        };
+        let idle = state.last_chunk_at.elapsed();
+        if idle < idle_timeout {
+            wait = idle_timeout - idle;
+            continue;
+        }
+        let Some(state) = finish_stream(&mut streams, stream_id) else {
+            return;
+        };
+        drop(streams);
+        unsafe { apple_ai_cancel_stream(stream_id) };
+        state.sink.send_from_producer(state.error_chunk(
+            GenerationErrorCode::Timeout,
+            format!("Timeout: stream produced no output for {idle_timeout_ms} ms"),
+        ));
+        return;
+    }
+}
+
+/// Stream a response for `prompt` through `callback(chunk)`.
+///
+/// Returns the stream id, which can be passed to `cancel_stream`. Any number
+/// of streams may run concurrently. Aborting `signal` cancels the stream the
+/// same way.
+#[napi]
+pub fn generate_response_stream(
+    env: Env,
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "(chunk: StreamChunk) => void")] callback: JsFunction,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+    #[napi(ts_arg_type = "AbortSignal | undefined")] signal: Option<JsObject>,
+) -> napi::Result<u32> {
+    ensure_initialized()?;
+    let options = NativeOptions::new(temperature, max_tokens, options)?;
+    if let Some(signal) = &signal {
+        if signal.get_named_property::<bool>("aborted")? {
+            return Err(napi::Error::new(
+                Status::Cancelled,
+                "AbortError".to_string(),
+            ));
+        }
+    }
+    let c_options = options.to_c_string()?;
+    let prompt_cstring = options.c_text(prompt, "Prompt")?;
+    let stream_id = register_stream(callback, &prompt_cstring, &options)?;
+
+    // invoke Swift stream (pointer valid due to prompt_cstring clone in state)
+    unsafe {
+        apple_ai_generate_response_stream_v2(
+            prompt_cstring.as_ptr(),
+            c_options.as_ptr(),
+            stream_id,
+            chunk_callback,
+        );
+    }
+    watch_stream_timeout(stream_id, &options);
+    if let Some(signal) = signal {
+        // Stream ids aren't reused, so an abort after the stream ended does nothing
+        on_abort(&env, &signal, move || {
+            let _ = cancel_stream(stream_id);
+        })?;
+    }
+    Ok(stream_id)
+}
+
+/// Stream the reply to a conversation history through `callback(chunk)`.
+///
+/// Takes the same `messages_json` as `generate_response_with_history` and
+/// signals chunks, errors and the end of the stream like
+/// `generate_response_stream`.
+#[napi]
+pub fn generate_response_with_history_stream(
+    messages_json: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "(chunk: StreamChunk) => void")] callback: JsFunction,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<u32> {
+    ensure_initialized()?;
+    let options = NativeOptions::new(temperature, max_tokens, options)?;
+    let c_options = options.to_c_string()?;
+    let json_cstring = validated_messages_json(&messages_json)?;
+    let stream_id = register_stream(callback, &json_cstring, &options)?;
+
+    unsafe {
+        apple_ai_generate_response_with_history_stream(
+            json_cstring.as_ptr(),
+            c_options.as_ptr(),
+            stream_id,
+            chunk_callback,
+        );
+    }
+    watch_stream_timeout(stream_id, &options);
+    Ok(stream_id)
+}
+
+/// Stop stream `stream_id` early. No-op for unknown or finished streams.
+///
+/// Chunks already queued are still delivered, followed by one final event
+/// with `cancelled` set whose `text` is everything generated so far (minus
+/// any incomplete trailing character). Nothing is generated after this
+/// returns. Safe to call from inside the stream callback.
+#[napi]
+pub fn cancel_stream(stream_id: u32) -> napi::Result<()> {
+    // Take the state out first so the lock isn't held across the FFI call
+    let state = finish_stream(&mut lock_streams(), stream_id);
+    if let Some(state) = state {
+        unsafe { apple_ai_cancel_stream(stream_id) };
+        // Readers just see the end of the stream
+        if let StreamSink::Callback(..) = state.sink {
+            let chunk = StreamChunk {
+                cancelled: true,
+                metrics: Some(state.metrics()),
+                timings: state.timings(),
+                finish_reason: Some("cancelled".to_string()),
+                metadata: state.metadata.clone(),
+                ..StreamChunk::end(String::new(), state.text.clone())
+            };
+            state.sink.send(chunk);
+        }
+    }
+    Ok(())
+}
+
+/// `cancel_stream` every running stream, e.g. on server shutdown. Returns
+/// how many were cancelled.
+#[napi]
+pub fn cancel_all_streams() -> napi::Result<u32> {
+    let ids = active_streams();
+    for &stream_id in &ids {
+        cancel_stream(stream_id)?;
+    }
+    Ok(ids.len() as u32)
+}
+
+// ---------------- Pull-based streams ----------------
+
+/// Chunks a `StreamReader` buffers before the producer has to wait.
+const STREAM_READER_CAPACITY: usize = 16;
+
+/// A stream consumed by pulling chunks with `next_chunk()`, e.g. from an
+/// async iterator.
+///
+/// At most `STREAM_READER_CAPACITY` chunks are buffered: generation pauses
+/// while the consumer falls behind.
+#[napi]
+pub struct StreamReader {
+    id: u32,
+    receiver: Arc<Mutex<Option<mpsc::Receiver<StreamChunk>>>>,
+}
+
+#[napi]
+impl StreamReader {
+    /// Stream id, as accepted by `cancel_stream`.
+    #[napi(getter)]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Resolve to the next chunk, or `null` once the stream has ended.
+    #[napi(ts_return_type = "Promise<StreamChunk | null>")]
+    pub fn next_chunk(&self) -> AsyncTask<NextChunkTask> {
+        AsyncTask::new(NextChunkTask {
+            receiver: Arc::clone(&self.receiver),
+        })
+    }
+
+    /// Stop the stream. Pending and later `next_chunk` calls resolve to `null`.
+    #[napi]
+    pub fn cancel(&self) {
+        // Dropping the registry's sender ends a `next_chunk` waiting for data
+        if finish_stream(&mut lock_streams(), self.id).is_some() {
+            unsafe { apple_ai_cancel_stream(self.id) };
+        }
+        // Dropping the receiver unblocks a producer waiting on a full queue
+        lock(&self.receiver).take();
+    }
+}
+
+impl Drop for StreamReader {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+pub struct NextChunkTask {
+    receiver: Arc<Mutex<Option<mpsc::Receiver<StreamChunk>>>>,
+}
+
+impl napi::Task for NextChunkTask {
+    type Output = Option<StreamChunk>;
+    type JsValue = Option<StreamChunk>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let receiver = lock(&self.receiver);
+        Ok(receiver.as_ref().and_then(|rx| rx.recv().ok()))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// Like `generate_response_stream`, but returns a `StreamReader` to pull
+/// chunks from instead of pushing them to a callback.
+#[napi]
+pub fn generate_response_stream_iter(
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<StreamReader> {
+    ensure_initialized()?;
+    let options = NativeOptions::new(temperature, max_tokens, options)?;
+    let c_options = options.to_c_string()?;
+    let prompt_cstring = options.c_text(prompt, "Prompt")?;
+    let capacity = options
+        .stream_buffer_size
+        .map_or(STREAM_READER_CAPACITY, |n| n as usize);
+    let (tx, rx) = mpsc::sync_channel(capacity);
+    let stream_id = register_sink(StreamSink::Channel(tx), &prompt_cstring, &options);
+
+    unsafe {
+        apple_ai_generate_response_stream_v2(
+            prompt_cstring.as_ptr(),
+            c_options.as_ptr(),
+            stream_id,
+            chunk_callback,
+        );
+    }
+    watch_stream_timeout(stream_id, &options);
+    Ok(StreamReader {
+        id: stream_id,
+        receiver: Arc::new(Mutex::new(Some(rx))),
+    })
+}
+
+// ---------------- Persistent sessions ----------------
+
+/// A conversation that keeps its context on the native side.
+///
+/// Each `respond` only sends the new turn; earlier turns are remembered by
+/// the underlying model session. Call `dispose()` when done — a session that
+/// is garbage collected without it is released on finalization.
+#[napi]
+pub struct Session {
+    id: u32,
+}
+
+#[napi]
+impl Session {
+    /// Create a session, optionally guided by system `instructions`.
+    #[napi(constructor)]
+    pub fn new(
+        #[napi(ts_arg_type = "string | undefined")] instructions: Option<String>,
+    ) -> napi::Result<Self> {
+        ensure_initialized()?;
+        let c_instructions = instructions.map(CString::new).transpose().map_err(|_| {
+            napi::Error::from_reason("Instructions contained null byte".to_string())
+        })?;
+        let id = unsafe {
+            apple_ai_session_create(
+                c_instructions
+                    .as_ref()
+                    .map_or(std::ptr::null(), |c| c.as_ptr()),
+            )
+        };
+        if id == 0 {
+            return Err(napi::Error::from_reason(availability_reason()));
+        }
+        Ok(Session { id })
+    }
+
+    /// Create a session whose context is `history`, as saved by
+    /// `export_history`; see `import_history`.
+    #[napi(factory)]
+    pub fn from_history(history: String) -> napi::Result<Self> {
+        let session = Session::new(None)?;
+        session.import_history(history)?;
+        Ok(session)
+    }
+
+    /// Native session id, or 0 once disposed.
+    #[napi(getter)]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Cap the tokens (prompt and reply, estimated like
+    /// `GenerationResult`'s counts) that this session's turns may use from
+    /// now on. Once they are used up, further turns reject with a
+    /// `BudgetExceeded` error; the turn that crosses the limit still
+    /// completes. `undefined` removes the cap.
+    #[napi]
+    pub fn set_token_budget(&self, tokens: Option<u32>) -> napi::Result<()> {
+        let id = self.live_id()?;
+        let mut budgets = lock(&SESSION_BUDGETS);
+        let budgets = budgets.get_or_insert_with(HashMap::new);
+        match tokens {
+            Some(tokens) => budgets.insert(id, tokens),
+            None => budgets.remove(&id),
+        };
+        Ok(())
+    }
+
+    /// Tokens left under `set_token_budget`, or `undefined` without a budget.
+    #[napi]
+    pub fn remaining_budget(&self) -> napi::Result<Option<u32>> {
+        Ok(session_budget(self.live_id()?))
+    }
+
+    /// Send the next user turn and resolve to the model's reply.
+    #[napi(ts_return_type = "Promise<string> & { requestId: number }")]
+    pub fn respond(
+        &self,
+        prompt: String,
+        #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+        #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+        #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+    ) -> napi::Result<TrackedTask<SessionRespondTask>> {
+        let task = SessionRespondTask {
+            session_id: self.live_id()?,
+            prompt,
+            options: NativeOptions::new(temperature, max_tokens, options)?,
+            handle: RequestHandle::new(),
+        };
+        Ok(TrackedTask::new(task))
+    }
+
+    /// Like `respond`, but streams the reply through `callback(chunk)`.
+    ///
+    /// Returns a stream id usable with `cancel_stream`.
+    #[napi]
+    pub fn respond_stream(
+        &self,
+        env: Env,
+        prompt: String,
+        #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+        #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+        #[napi(ts_arg_type = "(chunk: StreamChunk) => void")] callback: JsFunction,
+        #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+    ) -> napi::Result<u32> {
+        let session_id = self.live_id()?;
+        let options = NativeOptions::new(temperature, max_tokens, options)?;
+        if let Err(message) = check_session_budget(session_id) {
+            return Err(coded_error(
+                env,
+                GenerationErrorCode::BudgetExceeded,
+                message,
+            ));
+        }
+        let c_options = options.to_c_string()?;
+        let prompt_cstring = options.c_text(prompt, "Prompt")?;
+        let stream_id = register_stream(callback, &prompt_cstring, &options)?;
+        if let Some(state) = lock_streams().get_mut(&stream_id) {
+            state.budget_session = Some(session_id);
+        }
+
+        unsafe {
+            apple_ai_session_respond_stream(
+                session_id,
+                prompt_cstring.as_ptr(),
+                c_options.as_ptr(),
+                stream_id,
+                chunk_callback,
+            );
+        }
+        watch_stream_timeout(stream_id, &options);
+        Ok(stream_id)
+    }
+
+    /// Forget every turn so far, as for a new conversation under the same
+    /// instructions, while keeping the model loaded: cheaper than disposing
+    /// and creating a new session. Requests already running finish with the
+    /// old context.
+    #[napi]
+    pub fn reset(&self) -> napi::Result<()> {
+        if !unsafe { apple_ai_session_reset(self.live_id()?) } {
+            return Err(napi::Error::from_reason(format!(
+                "Unknown session {}",
+                self.id
+            )));
+        }
+        Ok(())
+    }
+
+    /// The conversation so far as a JSON array of `ChatMessage`s (the
+    /// `messages_json` format), to persist and restore with
+    /// `import_history` or `Session.from_history`. Instructions come out as
+    /// a `system` message, and imported turns keep their `name`. Tool calls
+    /// and their output aren't included.
+    #[napi]
+    pub fn export_history(&self) -> napi::Result<String> {
+        let id = self.live_id()?;
+        let ptr = unsafe { apple_ai_session_export(id) };
+        if ptr.is_null() {
+            return Err(napi::Error::from_reason(format!("Unknown session {id}")));
+        }
+        Ok(take_c_string(ptr))
+    }
+
+    /// Replace the session's context with the turns in `history`, a JSON
+    /// array of `ChatMessage`s such as `export_history` returns. A `system`
+    /// message becomes the instructions, kept by `reset`. Requests already
+    /// running finish with the old context.
+    #[napi]
+    pub fn import_history(&self, history: String) -> napi::Result<()> {
+        let id = self.live_id()?;
+        let messages: Vec<ChatMessage> = serde_json::from_str(&history).map_err(|e| {
+            napi::Error::new(Status::InvalidArg, format!("Invalid history JSON: {e}"))
+        })?;
+        // An empty history is a fresh conversation
+        if !messages.is_empty() {
+            validate_messages(&messages)?;
+        }
+        let json = serde_json::to_string(&messages)
+            .map_err(|e| napi::Error::from_reason(format!("Failed to encode history: {e}")))?;
+        let c_history = CString::new(json)
+            .map_err(|_| napi::Error::from_reason("History contained null byte".to_string()))?;
+        if !unsafe { apple_ai_session_load_history(id, c_history.as_ptr()) } {
+            return Err(napi::Error::from_reason(format!("Unknown session {id}")));
+        }
+        Ok(())
+    }
+
+    /// Release the native session. Safe to call more than once.
+    #[napi]
+    pub fn dispose(&mut self) {
+        if self.id != 0 {
+            unsafe { apple_ai_session_free(self.id) };
+            if let Some(budgets) = lock(&SESSION_BUDGETS).as_mut() {
+                budgets.remove(&self.id);
+            }
+            self.id = 0;
+        }
+    }
+}
+
+impl Session {
+    fn live_id(&self) -> napi::Result<u32> {
+        if self.id == 0 {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "Session has been disposed".to_string(),
+            ));
+        }
+        Ok(self.id)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.dispose();
+    }
+}
+
+/// Send `prompt` to the live session with id `session_id` (see `Session.id`)
+/// without going through the `Session` object.
+///
+/// The turn joins the session's context like `Session.respond`. Rejects with
+/// `InvalidInput` if the id is unknown or the session has been disposed.
+#[napi(ts_return_type = "Promise<string> & { requestId: number }")]
+pub fn generate_in_session(
+    session_id: u32,
+    prompt: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<SessionRespondTask>> {
+    if session_id == 0 {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Session has been disposed".to_string(),
+        ));
+    }
+    let task = SessionRespondTask {
+        session_id,
+        prompt,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+/// Tokens left for each session with a `set_token_budget`, by session id.
+static SESSION_BUDGETS: Mutex<Option<HashMap<u32, u32>>> = Mutex::new(None);
+
+fn session_budget(session_id: u32) -> Option<u32> {
+    lock(&SESSION_BUDGETS)
+        .as_ref()
+        .and_then(|budgets| budgets.get(&session_id).copied())
+}
+
+/// The `BudgetExceeded` message if session `session_id` has no tokens left.
+fn check_session_budget(session_id: u32) -> std::result::Result<(), String> {
+    match session_budget(session_id) {
+        Some(0) => Err(format!("Session {session_id} has used up its token budget")),
+        _ => Ok(()),
+    }
+}
+
+fn charge_session_budget(session_id: u32, tokens: u32) {
+    if let Some(budgets) = lock(&SESSION_BUDGETS).as_mut() {
+        if let Some(remaining) = budgets.get_mut(&session_id) {
+            *remaining = remaining.saturating_sub(tokens);
+        }
+    }
+}
+
+pub struct SessionRespondTask {
+    pub session_id: u32,
+    pub prompt: String,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for SessionRespondTask {
+    type Output = String;
+    type JsValue = JsString;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        check_session_budget(self.session_id).map_err(|message| {
+            let _ = self
+                .handle
+                .error_code
+                .set(GenerationErrorCode::BudgetExceeded);
+            napi::Error::from_reason(message)
+        })?;
+        let c_prompt = self
+            .options
+            .c_text(std::mem::take(&mut self.prompt), "Prompt")?;
+        if self.options.check_context_window {
+            // Only the new turn is counted: the transcript lives in Swift.
+            let prompt_tokens = unsafe { apple_ai_count_tokens(c_prompt.as_ptr()) };
+            check_context_window(prompt_tokens, &self.handle)?;
+        }
+        let result = run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_session_respond(self.session_id, c_prompt.as_ptr(), options, handle)
+        })?;
+        charge_session_budget(
+            self.session_id,
+            result
+                .prompt_tokens
+                .saturating_add(result.completion_tokens),
+        );
+        Ok(result.text)
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        env.create_string(&output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for SessionRespondTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Generate more of the last reply in session `session_id`, typically after
+/// it stopped with finish reason `length`, and resolve to just the new part
+/// with its own finish reason.
+///
+/// FoundationModels can't resume decoding, so this is a turn of its own
+/// asking the model to carry on, with the cut-off reply still in the
+/// session's context; the seam may not be perfectly smooth. It counts
+/// towards the session's token budget like `respond`.
+#[napi(ts_return_type = "Promise<GenerationResult> & { requestId: number }")]
+pub fn continue_generation(
+    session_id: u32,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<SessionContinueTask>> {
+    if session_id == 0 {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Session has been disposed".to_string(),
+        ));
+    }
+    let task = SessionContinueTask {
+        session_id,
+        options: NativeOptions::new(None, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
+
+pub struct SessionContinueTask {
+    pub session_id: u32,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for SessionContinueTask {
+    type Output = GenerationResult;
+    type JsValue = GenerationResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        check_session_budget(self.session_id).map_err(|message| {
+            let _ = self
+                .handle
+                .error_code
+                .set(GenerationErrorCode::BudgetExceeded);
+            napi::Error::from_reason(message)
+        })?;
+        let result = run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_session_continue(self.session_id, options, handle)
+        })?;
+        charge_session_budget(
+            self.session_id,
+            result
+                .prompt_tokens
+                .saturating_add(result.completion_tokens),
+        );
+        Ok(result)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for SessionContinueTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
 
-pub struct GenerateTask {
+// ---------------- Structured generation task ----------------
+
+pub struct GenerateStructuredTask {
     pub prompt: String,
+    pub schema_json: String,
     pub temperature: f64,
     pub max_tokens: i32,
 }
 
-impl napi::Task for GenerateTask {
+impl napi::Task for GenerateStructuredTask {
     type Output = String;
     type JsValue = JsString;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        ensure_initialized();
-        let c_prompt = CString::new(self.prompt.clone())
+        ensure_initialized()?;
+        let c_prompt = CString::new(std::mem::take(&mut self.prompt))
             .map_err(|_| napi::Error::from_reason("Prompt contained null byte".to_string()))?;
+        let c_schema = CString::new(std::mem::take(&mut self.schema_json))
+            .map_err(|_| napi::Error::from_reason("Schema contained null byte".to_string()))?;
         unsafe {
-            let result_ptr = apple_ai_generate_response(
+            let result_ptr = apple_ai_generate_response_structured(
                 c_prompt.as_ptr(),
+                c_schema.as_ptr(),
                 self.temperature as c_double,
                 self.max_tokens as c_int,
             );
@@ -150,210 +4336,803 @@ impl napi::Task for GenerateTask {
 }
 
 #[napi]
-pub fn generate_response(
+pub fn generate_response_structured(
     prompt: String,
+    schema_json: String,
     #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
     #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
-) -> napi::Result<AsyncTask<GenerateTask>> {
-    let task = GenerateTask {
+) -> napi::Result<AsyncTask<GenerateStructuredTask>> {
+    let defaults = default_params();
+    let task = GenerateStructuredTask {
         prompt,
-        temperature: temperature.unwrap_or(0.0),
-        max_tokens: max_tokens.unwrap_or(0),
+        schema_json,
+        temperature: validate_temperature(temperature.or(defaults.temperature))?,
+        max_tokens: validate_max_tokens(max_tokens.or(defaults.max_tokens))?,
     };
     Ok(AsyncTask::new(task))
 }
 
-// Task for history
-pub struct GenerateHistoryTask {
-    pub messages_json: String,
-    pub temperature: f64,
-    pub max_tokens: i32,
+pub struct StructuredTask {
+    pub prompt: String,
+    pub json_schema: String,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
 }
 
-impl napi::Task for GenerateHistoryTask {
+impl napi::Task for StructuredTask {
     type Output = String;
     type JsValue = JsString;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        ensure_initialized();
-        let c_json = CString::new(self.messages_json.clone())
-            .map_err(|_| napi::Error::from_reason("JSON contained null byte".to_string()))?;
-        unsafe {
-            let result_ptr = apple_ai_generate_response_with_history(
-                c_json.as_ptr(),
-                self.temperature as c_double,
-                self.max_tokens as c_int,
-            );
-            if result_ptr.is_null() {
-                return Err(napi::Error::from_reason(
-                    "Generation returned null".to_string(),
-                ));
-            }
-            Ok(take_c_string(result_ptr))
-        }
+        ensure_initialized()?;
+        let c_prompt = self
+            .options
+            .c_text(std::mem::take(&mut self.prompt), "Prompt")?;
+        let c_schema = CString::new(std::mem::take(&mut self.json_schema))
+            .map_err(|_| napi::Error::from_reason("Schema contained null byte".to_string()))?;
+        let result = run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_generate_structured(c_prompt.as_ptr(), c_schema.as_ptr(), options, handle)
+        })?;
+        Ok(result.text)
     }
 
     fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
         env.create_string(&output)
     }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
 }
 
-#[napi]
-pub fn generate_response_with_history(
-    messages_json: String,
+impl RequestTask for StructuredTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Generate an object conforming to `json_schema` and resolve to it as JSON.
+///
+/// Uses guided generation, so the output always validates against the
+/// schema. Rejects if the schema can't be converted or the model fails to
+/// produce a conforming object. Stop sequences are ignored.
+#[napi(ts_return_type = "Promise<string> & { requestId: number }")]
+pub fn generate_structured(
+    prompt: String,
+    json_schema: String,
     #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
     #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
-) -> napi::Result<AsyncTask<GenerateHistoryTask>> {
-    let task = GenerateHistoryTask {
-        messages_json,
-        temperature: temperature.unwrap_or(0.0),
-        max_tokens: max_tokens.unwrap_or(0),
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<StructuredTask>> {
+    let task = StructuredTask {
+        prompt,
+        json_schema,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
     };
-    Ok(AsyncTask::new(task))
+    Ok(TrackedTask::new(task))
+}
+
+/// Best-effort completion of truncated JSON `text`: close an unterminated
+/// string and every open array and object, dropping a dangling `,` or `:`.
+/// `None` if the result still doesn't parse, e.g. mid-number or mid-key.
+fn close_partial_json(text: &str) -> Option<String> {
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' => {
+                closers.pop();
+            }
+            _ => {}
+        }
+    }
+    let mut closed = text.to_string();
+    if in_string {
+        if escaped {
+            closed.pop();
+        }
+        closed.push('"');
+    } else {
+        closed.truncate(closed.trim_end().len());
+    }
+    if closed.ends_with(':') {
+        closed.push_str("null");
+    }
+    if closed.ends_with(',') {
+        closed.pop();
+    }
+    closed.extend(closers.iter().rev());
+    serde_json::from_str::<serde_json::Value>(&closed)
+        .is_ok()
+        .then_some(closed)
 }
 
-// Safe global stream state ---------------------------------------------------
+/// Stream an object conforming to `json_schema` through `callback(chunk)`.
+///
+/// Each chunk's `partial_object` holds the JSON generated so far, closed so
+/// it parses, for rendering fields as they arrive. The final event carries
+/// the complete object, or a `MalformedJson` error if it doesn't parse.
+/// Returns a stream id usable with `cancel_stream`. Stop sequences are
+/// ignored.
+#[napi]
+pub fn generate_structured_stream(
+    prompt: String,
+    json_schema: String,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "(chunk: StreamChunk) => void")] callback: JsFunction,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<u32> {
+    ensure_initialized()?;
+    let options = NativeOptions::new(temperature, max_tokens, options)?;
+    let c_options = options.to_c_string()?;
+    let c_schema = CString::new(json_schema)
+        .map_err(|_| napi::Error::from_reason("Schema contained null byte".to_string()))?;
+    let prompt_cstring = options.c_text(prompt, "Prompt")?;
+    let stream_id = register_stream(callback, &prompt_cstring, &options)?;
+    if let Some(state) = lock_streams().get_mut(&stream_id) {
+        state.structured = true;
+    }
 
-struct StreamState {
-    tsfn: ThreadsafeFunction<String, ErrorStrategy::CalleeHandled>,
-    _prompt: CString, // keeps the CString alive for the duration of the stream
+    unsafe {
+        apple_ai_generate_structured_stream(
+            prompt_cstring.as_ptr(),
+            c_schema.as_ptr(),
+            c_options.as_ptr(),
+            stream_id,
+            chunk_callback,
+        );
+    }
+    watch_stream_timeout(stream_id, &options);
+    Ok(stream_id)
 }
 
-static STREAM_STATE: OnceLock<Mutex<Option<StreamState>>> = OnceLock::new();
+// ---------------- Safety ----------------
 
-#[inline(always)]
-fn stream_state() -> &'static Mutex<Option<StreamState>> {
-    STREAM_STATE.get_or_init(|| Mutex::new(None))
+/// Verdict of Apple's safety guardrails on a prompt.
+#[napi(object)]
+#[derive(Deserialize)]
+pub struct SafetyResult {
+    /// The guardrails would block a generation for this prompt.
+    pub flagged: bool,
+    /// Violated categories. FoundationModels doesn't name them, so this is
+    /// always empty for now; see `reason`.
+    pub categories: Vec<String>,
+    /// What the guardrails reported, when flagged.
+    pub reason: Option<String>,
 }
 
-const ERROR_SENTINEL: u8 = 0x02;
+pub struct ClassifyPromptTask {
+    pub prompt: String,
+    pub handle: Arc<RequestHandle>,
+}
 
-extern "C" fn chunk_callback(ptr: *const c_char) {
-    // get mutex
-    let mutex = stream_state();
-    let mut guard = mutex.lock().unwrap();
+impl napi::Task for ClassifyPromptTask {
+    type Output = SafetyResult;
+    type JsValue = SafetyResult;
 
-    if let Some(state) = guard.as_mut() {
-        if ptr.is_null() {
-            // End of stream
-            let _ = state
-                .tsfn
-                .call(Ok("".to_string()), ThreadsafeFunctionCallMode::NonBlocking);
-            let _ = state.tsfn.clone().abort();
-            *guard = None;
-            return;
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        let c_prompt = CString::new(std::mem::take(&mut self.prompt))
+            .map_err(|_| napi::Error::from_reason("Prompt contained null byte".to_string()))?;
+        let _permit = GenerationPermit::acquire();
+        let result_ptr =
+            unsafe { apple_ai_classify_prompt(c_prompt.as_ptr(), self.handle.id.into()) };
+        self.handle.finished.store(true, Ordering::Release);
+        if result_ptr.is_null() {
+            return Err(napi::Error::from_reason(
+                "Classification failed".to_string(),
+            ));
         }
-
-        // Take ownership and free C string once here
-        let slice_owned = take_c_string(ptr as *mut c_char);
-        if slice_owned.is_empty() {
-            return;
+        let raw = take_c_string(result_ptr);
+        if let Ok(result) = serde_json::from_str(&raw) {
+            return Ok(result);
+        }
+        match serde_json::from_str::<NativeError>(&raw) {
+            Ok(error) => {
+                let _ = self
+                    .handle
+                    .error_code
+                    .set(GenerationErrorCode::from_code(error.code));
+                Err(napi::Error::from_reason(error.message))
+            }
+            Err(_) => Err(napi::Error::from_reason(raw)),
         }
+    }
 
-        let bytes = slice_owned.as_bytes();
-        if !bytes.is_empty() && bytes[0] == ERROR_SENTINEL {
-            let msg = String::from_utf8_lossy(&bytes[1..]).into_owned();
-            let _ = state.tsfn.call(
-                Err(napi::Error::from_reason(msg)),
-                ThreadsafeFunctionCallMode::NonBlocking,
-            );
-            return;
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
+}
+
+impl RequestTask for ClassifyPromptTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Screen `prompt` with Apple's safety guardrails before spending a full
+/// generation on it.
+///
+/// There is no standalone classifier in FoundationModels, so this runs the
+/// guardrails through a single-token generation: much cheaper than a real
+/// one, but it still takes a slot under `set_max_concurrency`. The output
+/// guardrails, which apply to what the model writes, aren't covered.
+#[napi(ts_return_type = "Promise<SafetyResult> & { requestId: number }")]
+pub fn classify_prompt(prompt: String) -> TrackedTask<ClassifyPromptTask> {
+    TrackedTask::new(ClassifyPromptTask {
+        prompt,
+        handle: RequestHandle::new(),
+    })
+}
+
+// ---------------- Tool calling ----------------
+
+/// A function the model may ask to call.
+#[napi(object)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema of the arguments object, as a JSON string.
+    pub parameters: String,
+}
+
+#[napi(object)]
+#[derive(Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    /// Arguments object as JSON, conforming to the tool's `parameters`.
+    pub arguments: String,
+}
+
+/// Tool definition as sent to Swift in `tools_json`.
+#[derive(Serialize)]
+struct ToolDefinition {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+fn tools_json(tools: Vec<ToolSpec>) -> napi::Result<CString> {
+    let invalid = |msg: String| napi::Error::new(Status::InvalidArg, msg);
+    let mut names = HashSet::new();
+    let mut definitions = Vec::with_capacity(tools.len());
+    for tool in tools {
+        if tool.name.is_empty() {
+            return Err(invalid("Tool names must not be empty".to_string()));
+        }
+        if !names.insert(tool.name.clone()) {
+            return Err(invalid(format!("Duplicate tool name '{}'", tool.name)));
         }
+        let parameters: serde_json::Value = serde_json::from_str(&tool.parameters)
+            .map_err(|e| invalid(format!("Invalid parameters for tool '{}': {e}", tool.name)))?;
+        if !parameters.is_object() {
+            return Err(invalid(format!(
+                "Parameters for tool '{}' must be a JSON Schema object",
+                tool.name
+            )));
+        }
+        definitions.push(ToolDefinition {
+            name: tool.name,
+            description: tool.description,
+            parameters,
+        });
+    }
+    let json = serde_json::to_string(&definitions)
+        .map_err(|e| napi::Error::from_reason(format!("Failed to encode tools: {e}")))?;
+    CString::new(json).map_err(|_| napi::Error::from_reason("JSON contained null byte".to_string()))
+}
+
+pub struct GenerateWithToolsTask {
+    pub prompt: String,
+    pub tools_json: CString,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
+}
+
+impl napi::Task for GenerateWithToolsTask {
+    type Output = GenerationResult;
+    type JsValue = GenerationResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        ensure_initialized()?;
+        let c_prompt = self
+            .options
+            .c_text(std::mem::take(&mut self.prompt), "Prompt")?;
+        run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_generate_with_tools(
+                c_prompt.as_ptr(),
+                self.tools_json.as_ptr(),
+                options,
+                handle,
+            )
+        })
+    }
 
-        let _ = state
-            .tsfn
-            .call(Ok(slice_owned), ThreadsafeFunctionCallMode::NonBlocking);
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
     }
 }
 
-#[napi]
-pub fn generate_response_stream(
+impl RequestTask for GenerateWithToolsTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Generate a response to `prompt`, letting the model call one of `tools`.
+///
+/// The tools aren't run here: if the model decides to call one, generation
+/// stops and the result carries the `tool_call` (with empty `text`) for the
+/// caller to fulfil, e.g. by prompting again with the tool's output.
+/// Otherwise it holds the final `text`. Stop sequences are ignored.
+#[napi(ts_return_type = "Promise<GenerationResult> & { requestId: number }")]
+pub fn generate_with_tools(
     prompt: String,
+    tools: Vec<ToolSpec>,
     #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
     #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
-    callback: JsFunction,
-) -> napi::Result<()> {
-    let ts_fn: ThreadsafeFunction<String, ErrorStrategy::CalleeHandled> = callback
-        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<String>| {
-            let env = ctx.env;
-            let js_string = env.create_string(&ctx.value)?;
-            Ok(vec![js_string]) // value will be passed as second arg, error injected automatically
-        })?;
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateWithToolsTask>> {
+    let task = GenerateWithToolsTask {
+        prompt,
+        tools_json: tools_json(tools)?,
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
+    };
+    Ok(TrackedTask::new(task))
+}
 
-    // Prepare stream state safely
-    let prompt_cstring = CString::new(prompt)?;
-    {
-        let mut guard = stream_state().lock().unwrap();
-        *guard = Some(StreamState {
-            tsfn: ts_fn,
-            _prompt: prompt_cstring.clone(),
-        });
+/// Stream a response to `prompt` through `callback(chunk)`, letting the
+/// model call one of `tools`.
+///
+/// When the model decides to call a tool, an event of kind `tool_call` with
+/// the complete `tool_call` arrives mid-stream and generation pauses until
+/// `submit_tool_result` hands back the tool's output; the model then
+/// carries on, possibly calling more tools, and the stream ends as usual.
+/// Returns the stream id. The idle timeout also runs while a tool result is
+/// outstanding.
+#[napi]
+#[allow(clippy::too_many_arguments)]
+pub fn generate_with_tools_stream(
+    env: Env,
+    prompt: String,
+    tools: Vec<ToolSpec>,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "(chunk: StreamChunk) => void")] callback: JsFunction,
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+    #[napi(ts_arg_type = "AbortSignal | undefined")] signal: Option<JsObject>,
+) -> napi::Result<u32> {
+    ensure_initialized()?;
+    let options = NativeOptions::new(temperature, max_tokens, options)?;
+    let c_tools = tools_json(tools)?;
+    if let Some(signal) = &signal {
+        if signal.get_named_property::<bool>("aborted")? {
+            return Err(napi::Error::new(
+                Status::Cancelled,
+                "AbortError".to_string(),
+            ));
+        }
     }
-
-    // invoke Swift stream (pointer valid due to prompt_cstring clone in state)
+    let c_options = options.to_c_string()?;
+    let prompt_cstring = options.c_text(prompt, "Prompt")?;
+    let stream_id = register_stream(callback, &prompt_cstring, &options)?;
     unsafe {
-        apple_ai_generate_response_stream(
+        apple_ai_generate_with_tools_stream(
             prompt_cstring.as_ptr(),
-            temperature.unwrap_or(0.0),
-            max_tokens.unwrap_or(0),
+            c_tools.as_ptr(),
+            c_options.as_ptr(),
+            stream_id,
             chunk_callback,
         );
     }
-    Ok(())
+    watch_stream_timeout(stream_id, &options);
+    if let Some(signal) = signal {
+        // Stream ids aren't reused, so an abort after the stream ended does nothing
+        on_abort(&env, &signal, move || {
+            let _ = cancel_stream(stream_id);
+        })?;
+    }
+    Ok(stream_id)
 }
 
-// ---------------- Structured generation task ----------------
+/// Resume stream `stream_id` after a `tool_call` event with the tool's
+/// output, e.g. a JSON string. With several calls outstanding, results are
+/// matched to calls in the order the calls were reported.
+#[napi]
+pub fn submit_tool_result(stream_id: u32, result: String) -> napi::Result<()> {
+    let c_result = CString::new(result)
+        .map_err(|_| napi::Error::from_reason("Tool result contained null byte".to_string()))?;
+    let not_waiting = || {
+        napi::Error::new(
+            Status::InvalidArg,
+            format!("Stream {stream_id} isn't waiting for a tool result"),
+        )
+    };
+    {
+        let mut streams = lock_streams();
+        let state = streams
+            .get_mut(&stream_id)
+            .filter(|state| state.pending_tool_calls > 0)
+            .ok_or_else(not_waiting)?;
+        state.pending_tool_calls -= 1;
+        // The model resumes now, so the idle wait starts over
+        state.last_chunk_at = Instant::now();
+    }
+    if unsafe { apple_ai_submit_tool_result(stream_id, c_result.as_ptr()) } {
+        Ok(())
+    } else {
+        Err(not_waiting())
+    }
+}
 
-pub struct GenerateStructuredTask {
+// ---------------- Image input ----------------
+
+const IMAGE_FORMATS: [&str; 2] = ["png", "jpeg"];
+
+pub struct GenerateWithImageTask {
     pub prompt: String,
-    pub schema_json: String,
-    pub temperature: f64,
-    pub max_tokens: i32,
+    pub image: Vec<u8>,
+    pub format: CString,
+    pub options: NativeOptions,
+    pub handle: Arc<RequestHandle>,
 }
 
-impl napi::Task for GenerateStructuredTask {
+impl napi::Task for GenerateWithImageTask {
     type Output = String;
     type JsValue = JsString;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        ensure_initialized();
-        let c_prompt = CString::new(self.prompt.clone())
-            .map_err(|_| napi::Error::from_reason("Prompt contained null byte".to_string()))?;
-        let c_schema = CString::new(self.schema_json.clone())
-            .map_err(|_| napi::Error::from_reason("Schema contained null byte".to_string()))?;
-        unsafe {
-            let result_ptr = apple_ai_generate_response_structured(
+        ensure_initialized()?;
+        let c_prompt = self
+            .options
+            .c_text(std::mem::take(&mut self.prompt), "Prompt")?;
+        run_generation(&self.options, &self.handle, |options, handle| unsafe {
+            apple_ai_generate_with_image(
                 c_prompt.as_ptr(),
-                c_schema.as_ptr(),
-                self.temperature as c_double,
-                self.max_tokens as c_int,
-            );
-            if result_ptr.is_null() {
-                return Err(napi::Error::from_reason(
-                    "Generation returned null".to_string(),
-                ));
-            }
-            Ok(take_c_string(result_ptr))
-        }
+                self.image.as_ptr(),
+                self.image.len(),
+                self.format.as_ptr(),
+                options,
+                handle,
+            )
+        })
+        .map(|result| result.text)
     }
 
     fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
         env.create_string(&output)
     }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.handle.coded_error(env, err))
+    }
 }
 
-#[napi]
-pub fn generate_response_structured(
+impl RequestTask for GenerateWithImageTask {
+    fn handle(&self) -> &Arc<RequestHandle> {
+        &self.handle
+    }
+}
+
+/// Generate a response to `prompt` about an encoded `image` (`png` or `jpeg`
+/// bytes, as named by `format`), e.g. to describe or transcribe it.
+///
+/// Rejects with `MultimodalUnsupported` when the model can't take images,
+/// which is currently the case for Apple's on-device model.
+#[napi(ts_return_type = "Promise<string> & { requestId: number }")]
+pub fn generate_response_with_image(
     prompt: String,
-    schema_json: String,
+    image: Buffer,
+    #[napi(ts_arg_type = "'png' | 'jpeg'")] format: String,
     #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
     #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
-) -> napi::Result<AsyncTask<GenerateStructuredTask>> {
-    let task = GenerateStructuredTask {
+    #[napi(ts_arg_type = "GenerationOptions | undefined")] options: Option<GenerationOptions>,
+) -> napi::Result<TrackedTask<GenerateWithImageTask>> {
+    if image.is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Image must not be empty".to_string(),
+        ));
+    }
+    if !IMAGE_FORMATS.contains(&format.as_str()) {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("Image format must be 'png' or 'jpeg', got '{format}'"),
+        ));
+    }
+    let task = GenerateWithImageTask {
         prompt,
-        schema_json,
-        temperature: temperature.unwrap_or(0.0),
-        max_tokens: max_tokens.unwrap_or(0),
+        image: image.to_vec(),
+        format: CString::new(format).unwrap_or_default(),
+        options: NativeOptions::new(temperature, max_tokens, options)?,
+        handle: RequestHandle::new(),
     };
-    Ok(AsyncTask::new(task))
+    Ok(TrackedTask::new(task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_utf8_holds_back_a_split_character() {
+        let smiley = "😀".as_bytes();
+        let mut pending = smiley[..3].to_vec();
+        assert_eq!(drain_utf8(&mut pending), "");
+        assert_eq!(pending, &smiley[..3]);
+
+        pending.extend_from_slice(&smiley[3..]);
+        assert_eq!(drain_utf8(&mut pending), "😀");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn drain_utf8_emits_text_before_a_split_character() {
+        let mut pending = b"caf\xC3".to_vec();
+        assert_eq!(drain_utf8(&mut pending), "caf");
+        assert_eq!(pending, b"\xC3");
+
+        pending.push(0xA9);
+        assert_eq!(drain_utf8(&mut pending), "é");
+    }
+
+    #[test]
+    fn flush_utf8_replaces_a_dangling_tail() {
+        let mut pending = "é".as_bytes()[..1].to_vec();
+        assert_eq!(drain_utf8(&mut pending), "");
+        assert_eq!(flush_utf8(&mut pending), "\u{FFFD}");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn is_transient_failure_retries_only_a_missing_or_busy_result() {
+        let handle = RequestHandle::new();
+        assert!(is_transient_failure(None, &handle));
+        assert!(is_transient_failure(
+            Some(r#"{"code":5,"message":"rate limited"}"#),
+            &handle
+        ));
+        assert!(!is_transient_failure(
+            Some(r#"{"code":2,"message":"guardrail"}"#),
+            &handle
+        ));
+        assert!(!is_transient_failure(Some(r#"{"text":"hi"}"#), &handle));
+    }
+
+    #[test]
+    fn is_transient_failure_never_retries_a_stopped_request() {
+        let cancelled = RequestHandle::new();
+        cancelled.cancelled.store(true, Ordering::Release);
+        assert!(!is_transient_failure(None, &cancelled));
+
+        let timed_out = RequestHandle::new();
+        timed_out.timed_out.store(true, Ordering::Release);
+        assert!(!is_transient_failure(None, &timed_out));
+        assert!(!is_transient_failure(
+            Some(r#"{"code":6,"message":"busy"}"#),
+            &timed_out
+        ));
+    }
+
+    #[test]
+    fn close_partial_json_closes_a_truncated_string() {
+        assert_eq!(
+            close_partial_json(r#"{"name": "Al"#).as_deref(),
+            Some(r#"{"name": "Al"}"#)
+        );
+        // A key cut short can't be closed into valid JSON
+        assert_eq!(close_partial_json(r#"{"na"#), None);
+    }
+
+    #[test]
+    fn close_partial_json_respects_escapes_inside_strings() {
+        assert_eq!(
+            close_partial_json(r#"{"q": "say \"hi"#).as_deref(),
+            Some(r#"{"q": "say \"hi"}"#)
+        );
+        // A dangling backslash is dropped rather than escaping the closing quote
+        assert_eq!(
+            close_partial_json(r#"{"q": "a\"#).as_deref(),
+            Some(r#"{"q": "a"}"#)
+        );
+        // Brackets inside a string don't need closing
+        assert_eq!(
+            close_partial_json(r#"{"s": "}{["#).as_deref(),
+            Some(r#"{"s": "}{["}"#)
+        );
+    }
+
+    #[test]
+    fn close_partial_json_closes_nested_containers_in_order() {
+        assert_eq!(
+            close_partial_json(r#"{"a": [1, {"b": [2"#).as_deref(),
+            Some(r#"{"a": [1, {"b": [2]}]}"#)
+        );
+    }
+
+    #[test]
+    fn close_partial_json_completes_a_trailing_comma_or_colon() {
+        assert_eq!(
+            close_partial_json(r#"{"a": 1,"#).as_deref(),
+            Some(r#"{"a": 1}"#)
+        );
+        assert_eq!(close_partial_json("[1, 2, ").as_deref(), Some("[1, 2]"));
+        assert_eq!(
+            close_partial_json(r#"{"a": "#).as_deref(),
+            Some(r#"{"a":null}"#)
+        );
+    }
+
+    #[test]
+    fn close_partial_json_keeps_complete_input() {
+        let complete = r#"{"a": [1, "x"], "b": {}}"#;
+        assert_eq!(close_partial_json(complete).as_deref(), Some(complete));
+    }
+
+    fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn render_prompt_substitutes_placeholders() {
+        let rendered = render_prompt(
+            "Hi {{name}}, you are {{ age }}. {{name}}!".to_string(),
+            variables(&[("name", "Ada"), ("age", "36")]),
+        );
+        assert_eq!(rendered.unwrap(), "Hi Ada, you are 36. Ada!");
+        // Substituted values aren't scanned for placeholders again
+        let rendered = render_prompt(
+            "{{a}}".to_string(),
+            variables(&[("a", "{{b}}"), ("b", "no")]),
+        );
+        assert_eq!(rendered.unwrap(), "{{b}}");
+    }
+
+    #[test]
+    fn render_prompt_rejects_a_missing_variable() {
+        let err = render_prompt("Hi {{name}}".to_string(), HashMap::new()).unwrap_err();
+        assert_eq!(err.status, Status::InvalidArg);
+        assert_eq!(err.reason, "No value for placeholder '{{name}}'");
+    }
+
+    #[test]
+    fn render_prompt_rejects_an_unclosed_placeholder() {
+        let err =
+            render_prompt("Hi {{name".to_string(), variables(&[("name", "Ada")])).unwrap_err();
+        assert_eq!(err.status, Status::InvalidArg);
+        assert_eq!(err.reason, "Unclosed '{{' in prompt template");
+    }
+
+    #[test]
+    fn render_prompt_rejects_an_embedded_nul() {
+        let err = render_prompt("{{x}}".to_string(), variables(&[("x", "a\0b")])).unwrap_err();
+        assert_eq!(err.reason, "Rendered prompt contained null byte");
+    }
+
+    #[test]
+    fn truncate_chars_cuts_on_a_character_boundary() {
+        let mut text = "héllo😀!".to_string();
+        assert!(truncate_chars(&mut text, 6));
+        assert_eq!(text, "héllo😀");
+        assert!(truncate_chars(&mut text, 2));
+        assert_eq!(text, "hé");
+    }
+
+    #[test]
+    fn truncate_chars_leaves_short_enough_text_alone() {
+        let mut text = "日本語".to_string();
+        assert!(!truncate_chars(&mut text, 3));
+        assert!(!truncate_chars(&mut text, 10));
+        assert_eq!(text, "日本語");
+    }
+
+    #[test]
+    fn language_info_parses_tags() {
+        let cases = [
+            ("en", "en", None),
+            ("en-US", "en", Some("US")),
+            ("en_GB", "en", Some("GB")),
+            ("zh-Hans-CN", "zh", Some("CN")),
+            ("zh-Hant", "zh", None),
+            ("es-419", "es", Some("419")),
+            ("PT-br", "pt", Some("BR")),
+            ("sr_Latn_RS", "sr", Some("RS")),
+        ];
+        for (tag, language, region) in cases {
+            let info = LanguageInfo::from_tag(tag.to_string());
+            assert_eq!(info.tag, tag);
+            assert_eq!(info.language, language, "language of {tag}");
+            assert_eq!(info.region.as_deref(), region, "region of {tag}");
+        }
+    }
+
+    #[test]
+    fn validate_temperature_accepts_the_range_bounds() {
+        assert_eq!(validate_temperature(None).unwrap(), 0.0);
+        assert_eq!(validate_temperature(Some(0.0)).unwrap(), 0.0);
+        assert_eq!(validate_temperature(Some(2.0)).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn validate_temperature_rejects_values_outside_the_range() {
+        for temperature in [-0.0001, 2.0001, f64::NAN] {
+            let err = validate_temperature(Some(temperature)).unwrap_err();
+            assert_eq!(err.status, Status::InvalidArg);
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn supported_languages_are_queried_once_until_refreshed() {
+        let queries = || mock::SUPPORTED_LANGUAGE_QUERIES.load(Ordering::Relaxed);
+        clear_caches();
+        let before = queries();
+        get_supported_languages().unwrap();
+        get_supported_languages().unwrap();
+        assert_eq!(queries() - before, 1);
+        refresh_supported_languages().unwrap();
+        assert_eq!(queries() - before, 2);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn generations_end_at_a_stop_sequence_or_max_tokens() {
+        let generate = |max_tokens: i32, stop_sequences: Vec<String>| {
+            let options = GenerationOptions {
+                stop_sequences: Some(stop_sequences),
+                ..Default::default()
+            };
+            let options = NativeOptions::new(None, Some(max_tokens), Some(options)).unwrap();
+            let prompt = CString::new("the weather today").unwrap();
+            run_generation(
+                &options,
+                &RequestHandle::new(),
+                |c_options, handle| unsafe {
+                    apple_ai_generate_with_instructions(
+                        c"".as_ptr(),
+                        prompt.as_ptr(),
+                        c_options,
+                        handle,
+                    )
+                },
+            )
+            .unwrap()
+        };
+        let whole = generate(0, Vec::new());
+        assert_eq!(whole.text, "Mock response to: the weather today");
+        assert_eq!(whole.finish_reason, "stop");
+        let stopped = generate(0, vec!["weather".to_string()]);
+        assert_eq!(stopped.text, "Mock response to: the ");
+        assert_eq!(stopped.finish_reason, "stop_sequence");
+        let limited = generate(3, vec!["weather".to_string()]);
+        assert_eq!(limited.text, "Mock response ");
+        assert_eq!(limited.finish_reason, "length");
+    }
 }
@@ -0,0 +1,504 @@
+//! Pure-Rust stand-ins for the Swift `appleai` library, enabled by the `mock`
+//! feature so the addon builds and runs without Apple hardware (e.g. on CI).
+//!
+//! Every function mirrors the signature and memory contract of its Swift
+//! counterpart: returned strings are `malloc`ed and released by the caller
+//! through `apple_ai_free_string`, and streams deliver their chunks from another
+//! thread, ending with NULL.
+//! Responses are deterministic: `Mock response to: <prompt>`.
+
+use libc::{c_char, c_double, c_int};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use crate::{estimate_tokens, lock};
+
+const CONTEXT_WINDOW_TOKENS: c_int = 4096;
+
+static NEXT_SESSION_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Calls to `apple_ai_get_supported_languages_count`, so tests can check
+/// the language caching.
+pub(crate) static SUPPORTED_LANGUAGE_QUERIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Streams waiting on `apple_ai_submit_tool_result`, by stream id.
+static TOOL_RESULTS: Mutex<Option<HashMap<u32, mpsc::Sender<String>>>> = Mutex::new(None);
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MockOptions {
+    response_format: Option<String>,
+    #[serde(default)]
+    echo_prompt: bool,
+    #[serde(default)]
+    max_tokens: i32,
+    #[serde(default)]
+    stop_sequences: Vec<String>,
+}
+
+/// A reply as the model would end it: cut once it reaches `max_tokens`, then
+/// before the first stop sequence.
+struct MockReply {
+    text: String,
+    hit_stop_sequence: bool,
+}
+
+impl MockReply {
+    fn new(mut text: String, options: &MockOptions) -> Self {
+        if let Ok(limit @ 1..) = u32::try_from(options.max_tokens) {
+            let mut end = 0;
+            for word in text.split_inclusive(' ') {
+                if estimate_tokens(&text[..end]) >= limit {
+                    break;
+                }
+                end += word.len();
+            }
+            text.truncate(end);
+        }
+        let stop = options
+            .stop_sequences
+            .iter()
+            .filter(|stop| !stop.is_empty())
+            .filter_map(|stop| text.find(stop.as_str()))
+            .min();
+        if let Some(end) = stop {
+            text.truncate(end);
+        }
+        MockReply {
+            text,
+            hit_stop_sequence: stop.is_some(),
+        }
+    }
+
+    /// A reply no option shortens.
+    fn whole(text: String) -> Self {
+        MockReply {
+            text,
+            hit_stop_sequence: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MockMessage {
+    content: String,
+}
+
+unsafe fn read(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+fn malloc_string(text: &str) -> *mut c_char {
+    let c_text = CString::new(text.replace('\0', "")).unwrap_or_default();
+    unsafe { libc::strdup(c_text.as_ptr()) }
+}
+
+unsafe fn read_options(options_json: *const c_char) -> MockOptions {
+    serde_json::from_str(&read(options_json)).unwrap_or_default()
+}
+
+/// The reply to `prompt`, as a JSON object if the options ask for one.
+unsafe fn mock_reply(prompt: &str, options_json: *const c_char) -> MockReply {
+    let options = read_options(options_json);
+    let reply = format!("Mock response to: {prompt}");
+    let text = if options.response_format.as_deref() == Some("json") {
+        json!({ "response": reply }).to_string()
+    } else {
+        reply
+    };
+    MockReply::new(text, &options)
+}
+
+fn usage_json(reply: &MockReply, prompt_tokens: u32) -> *mut c_char {
+    let mut json = json!({
+        "text": reply.text,
+        "promptTokens": prompt_tokens,
+        "completionTokens": estimate_tokens(&reply.text),
+    });
+    if reply.hit_stop_sequence {
+        json["finishReason"] = "stop_sequence".into();
+    }
+    malloc_string(&json.to_string())
+}
+
+fn last_message(messages_json: &str) -> Option<String> {
+    let messages: Vec<MockMessage> = serde_json::from_str(messages_json).ok()?;
+    messages.into_iter().next_back().map(|m| m.content)
+}
+
+/// Emit the reply word by word on a separate thread, then the end marker.
+fn stream_words(reply: MockReply, stream_id: u32, on_chunk: extern "C" fn(u32, *const c_char)) {
+    thread::spawn(move || send_words(&reply, stream_id, on_chunk));
+}
+
+fn send_words(reply: &MockReply, stream_id: u32, on_chunk: extern "C" fn(u32, *const c_char)) {
+    for word in reply.text.split_inclusive(' ') {
+        on_chunk(stream_id, malloc_string(word));
+    }
+    if reply.hit_stop_sequence {
+        on_chunk(stream_id, malloc_string("\u{3}stop_sequence"));
+    }
+    on_chunk(stream_id, std::ptr::null());
+}
+
+/// Stream the reply to `prompt` like `stream_words`, first echoing `echoed`
+/// if the options ask for `echo_prompt`.
+unsafe fn stream_reply(
+    prompt: &str,
+    echoed: String,
+    options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    let options = read_options(options_json);
+    let reply = mock_reply(prompt, options_json);
+    thread::spawn(move || {
+        if options.echo_prompt {
+            on_chunk(stream_id, malloc_string(&format!("\u{5}{echoed}")));
+        }
+        send_words(&reply, stream_id, on_chunk);
+    });
+}
+
+pub unsafe fn apple_ai_init() -> bool {
+    true
+}
+
+pub unsafe fn apple_ai_shutdown() {}
+
+pub unsafe fn apple_ai_free_string(ptr: *mut c_char) {
+    libc::free(ptr as *mut _);
+}
+
+pub unsafe fn apple_ai_check_availability() -> c_int {
+    1
+}
+
+pub unsafe fn apple_ai_get_availability_reason() -> *mut c_char {
+    malloc_string("mock")
+}
+
+pub unsafe fn apple_ai_get_availability_reason_code() -> c_int {
+    0
+}
+
+pub unsafe fn apple_ai_prewarm() -> bool {
+    true
+}
+
+pub unsafe fn apple_ai_get_supported_languages_count() -> c_int {
+    SUPPORTED_LANGUAGE_QUERIES.fetch_add(1, Ordering::Relaxed);
+    1
+}
+
+pub unsafe fn apple_ai_get_supported_language(index: c_int) -> *mut c_char {
+    if index == 0 {
+        malloc_string("en-US")
+    } else {
+        std::ptr::null_mut()
+    }
+}
+
+/// Everything non-empty is English.
+pub unsafe fn apple_ai_detect_language(text: *const c_char, _max_candidates: c_int) -> *mut c_char {
+    if read(text).trim().is_empty() {
+        malloc_string("[]")
+    } else {
+        malloc_string(r#"[{"language":"en","confidence":1.0}]"#)
+    }
+}
+
+/// Letter frequencies, normalized to unit length.
+pub unsafe fn apple_ai_generate_embedding(text: *const c_char) -> *mut c_char {
+    let mut vector = [0.0f64; 26];
+    for c in read(text).to_ascii_lowercase().bytes() {
+        if c.is_ascii_lowercase() {
+            vector[usize::from(c - b'a')] += 1.0;
+        }
+    }
+    let norm = vector.iter().map(|v| v * v).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    malloc_string(&json!(vector).to_string())
+}
+
+pub unsafe fn apple_ai_get_model_name() -> *mut c_char {
+    malloc_string("mock")
+}
+
+pub unsafe fn apple_ai_get_model_version() -> *mut c_char {
+    malloc_string("")
+}
+
+pub unsafe fn apple_ai_get_supported_language_tag(index: c_int) -> *mut c_char {
+    apple_ai_get_supported_language(index)
+}
+
+pub unsafe fn apple_ai_last_generation_on_device() -> bool {
+    true
+}
+
+// Unknown, as from the Swift layer
+pub unsafe fn apple_ai_get_model_memory_usage() -> i64 {
+    -1
+}
+
+/// Streaming and tools, like the Swift layer; no vision.
+pub unsafe fn apple_ai_supports_feature(feature: c_int) -> bool {
+    matches!(feature, 0 | 1)
+}
+
+pub unsafe fn apple_ai_get_context_window() -> c_int {
+    CONTEXT_WINDOW_TOKENS
+}
+
+pub unsafe fn apple_ai_count_tokens(text: *const c_char) -> c_int {
+    estimate_tokens(&read(text)) as c_int
+}
+
+pub unsafe fn apple_ai_count_tokens_for_messages(messages_json: *const c_char) -> c_int {
+    match serde_json::from_str::<Vec<MockMessage>>(&read(messages_json)) {
+        Ok(messages) => messages
+            .iter()
+            .map(|m| estimate_tokens(&m.content))
+            .sum::<u32>() as c_int,
+        Err(_) => -1,
+    }
+}
+
+// Mock generations complete immediately, so there is never anything to cancel
+pub unsafe fn apple_ai_cancel_request(_handle: u64) {}
+
+pub unsafe fn apple_ai_generate_with_instructions(
+    instructions: *const c_char,
+    prompt: *const c_char,
+    options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    let prompt = read(prompt);
+    let prompt_tokens = estimate_tokens(&read(instructions)) + estimate_tokens(&prompt);
+    usage_json(&mock_reply(&prompt, options_json), prompt_tokens)
+}
+
+pub unsafe fn apple_ai_generate_response_with_history_v2(
+    messages_json: *const c_char,
+    options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    let messages: Vec<MockMessage> = serde_json::from_str(&read(messages_json)).unwrap_or_default();
+    let prompt_tokens = messages.iter().map(|m| estimate_tokens(&m.content)).sum();
+    let prompt = messages.last().map_or("", |m| m.content.as_str());
+    usage_json(&mock_reply(prompt, options_json), prompt_tokens)
+}
+
+pub unsafe fn apple_ai_generate_response_stream_v2(
+    prompt: *const c_char,
+    options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    let prompt = read(prompt);
+    stream_reply(&prompt, prompt.clone(), options_json, stream_id, on_chunk);
+}
+
+pub unsafe fn apple_ai_generate_response_with_history_stream(
+    messages_json: *const c_char,
+    options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    let messages_json = read(messages_json);
+    let prompt = last_message(&messages_json).unwrap_or_default();
+    stream_reply(&prompt, messages_json, options_json, stream_id, on_chunk);
+}
+
+// Chunks of a cancelled stream go to an unknown id and are simply freed;
+// one waiting on a tool result gives up
+pub unsafe fn apple_ai_cancel_stream(stream_id: u32) {
+    if let Some(waiting) = lock(&TOOL_RESULTS).as_mut() {
+        waiting.remove(&stream_id);
+    }
+}
+
+pub unsafe fn apple_ai_session_create(_instructions: *const c_char) -> u32 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub unsafe fn apple_ai_session_respond(
+    _session_id: u32,
+    prompt: *const c_char,
+    options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    let prompt = read(prompt);
+    usage_json(&mock_reply(&prompt, options_json), estimate_tokens(&prompt))
+}
+
+pub unsafe fn apple_ai_session_respond_stream(
+    _session_id: u32,
+    prompt: *const c_char,
+    options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    let prompt = read(prompt);
+    stream_reply(&prompt, prompt.clone(), options_json, stream_id, on_chunk);
+}
+
+pub unsafe fn apple_ai_session_continue(
+    _session_id: u32,
+    options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    usage_json(&mock_reply("continue", options_json), 0)
+}
+
+// Mock sessions keep no transcript
+pub unsafe fn apple_ai_session_reset(_session_id: u32) -> bool {
+    true
+}
+
+pub unsafe fn apple_ai_session_export(_session_id: u32) -> *mut c_char {
+    malloc_string("[]")
+}
+
+pub unsafe fn apple_ai_session_load_history(
+    _session_id: u32,
+    _messages_json: *const c_char,
+) -> bool {
+    true
+}
+
+pub unsafe fn apple_ai_session_free(_session_id: u32) {}
+
+/// Always the empty object; the mock doesn't interpret schemas.
+pub unsafe fn apple_ai_generate_structured(
+    prompt: *const c_char,
+    _schema_json: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    usage_json(
+        &MockReply::whole("{}".to_string()),
+        estimate_tokens(&read(prompt)),
+    )
+}
+
+/// Streams `{"response": ...}` with the usual reply, whatever the schema.
+pub unsafe fn apple_ai_generate_structured_stream(
+    prompt: *const c_char,
+    _schema_json: *const c_char,
+    _options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    let reply = json!({ "response": format!("Mock response to: {}", read(prompt)) });
+    stream_words(MockReply::whole(reply.to_string()), stream_id, on_chunk);
+}
+
+pub unsafe fn apple_ai_generate_response_structured(
+    _prompt: *const c_char,
+    _schema_json: *const c_char,
+    _temperature: c_double,
+    _max_tokens: c_int,
+) -> *mut c_char {
+    malloc_string(r#"{"text":"{}","object":{}}"#)
+}
+
+#[derive(Deserialize)]
+struct MockTool {
+    name: String,
+}
+
+/// Calls the first tool named in the prompt, with no arguments; otherwise
+/// answers as usual.
+pub unsafe fn apple_ai_generate_with_tools(
+    prompt: *const c_char,
+    tools_json: *const c_char,
+    options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    let prompt = read(prompt);
+    let tools: Vec<MockTool> = serde_json::from_str(&read(tools_json)).unwrap_or_default();
+    let Some(tool) = tools.iter().find(|t| prompt.contains(&t.name)) else {
+        return usage_json(&mock_reply(&prompt, options_json), estimate_tokens(&prompt));
+    };
+    let json = json!({
+        "text": "",
+        "promptTokens": estimate_tokens(&prompt),
+        "completionTokens": 0,
+        "toolCall": { "name": tool.name, "arguments": "{}" },
+    });
+    malloc_string(&json.to_string())
+}
+
+/// Like `apple_ai_generate_with_tools`, but the call goes out on the stream
+/// and the reply, which quotes the tool's result, follows once it arrives.
+pub unsafe fn apple_ai_generate_with_tools_stream(
+    prompt: *const c_char,
+    tools_json: *const c_char,
+    options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    let prompt = read(prompt);
+    let tools: Vec<MockTool> = serde_json::from_str(&read(tools_json)).unwrap_or_default();
+    let Some(tool) = tools.into_iter().find(|t| prompt.contains(&t.name)) else {
+        stream_words(mock_reply(&prompt, options_json), stream_id, on_chunk);
+        return;
+    };
+    let (result_tx, result_rx) = mpsc::channel();
+    lock(&TOOL_RESULTS)
+        .get_or_insert_with(HashMap::new)
+        .insert(stream_id, result_tx);
+    let options = read(options_json);
+    thread::spawn(move || {
+        let call = json!({ "name": tool.name, "arguments": "{}" });
+        on_chunk(stream_id, malloc_string(&format!("\u{4}{call}")));
+        // Cancelled while waiting: nothing more to send
+        let Ok(result) = result_rx.recv() else { return };
+        let prompt = format!("{prompt} ({} returned {result})", tool.name);
+        let options = CString::new(options).unwrap_or_default();
+        stream_words(mock_reply(&prompt, options.as_ptr()), stream_id, on_chunk);
+    });
+}
+
+pub unsafe fn apple_ai_submit_tool_result(stream_id: u32, result: *const c_char) -> bool {
+    let waiting = lock(&TOOL_RESULTS)
+        .as_mut()
+        .and_then(|waiting| waiting.remove(&stream_id));
+    match waiting {
+        Some(result_tx) => result_tx.send(read(result)).is_ok(),
+        None => false,
+    }
+}
+
+// Nothing is flagged
+pub unsafe fn apple_ai_classify_prompt(_prompt: *const c_char, _handle: u64) -> *mut c_char {
+    malloc_string(r#"{"flagged":false,"categories":[]}"#)
+}
+
+/// Fails like the real backend: vision isn't among the mock's features either.
+pub unsafe fn apple_ai_generate_with_image(
+    _prompt: *const c_char,
+    _image_data: *const u8,
+    _image_len: usize,
+    _format: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    let error = json!({
+        "code": 10,
+        "message": "The on-device model doesn't accept image input",
+    });
+    malloc_string(&error.to_string())
+}
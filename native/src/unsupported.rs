@@ -0,0 +1,250 @@
+//! Stand-ins for the Swift `appleai` library on platforms other than macOS,
+//! where it doesn't exist.
+//!
+//! They let the addon load anywhere so apps can feature-detect at runtime:
+//! `apple_ai_init` fails, which makes every entry point reject with a
+//! "not supported" error, and availability reports `UnsupportedPlatform`.
+//! The remaining functions are never reached past the init check and just
+//! return empty results.
+
+use libc::{c_char, c_double, c_int};
+use std::ffi::CString;
+
+fn malloc_string(text: &str) -> *mut c_char {
+    let c_text = CString::new(text).unwrap_or_default();
+    unsafe { libc::strdup(c_text.as_ptr()) }
+}
+
+pub unsafe fn apple_ai_init() -> bool {
+    false
+}
+
+pub unsafe fn apple_ai_shutdown() {}
+
+pub unsafe fn apple_ai_free_string(ptr: *mut c_char) {
+    libc::free(ptr as *mut _);
+}
+
+pub unsafe fn apple_ai_check_availability() -> c_int {
+    0
+}
+
+pub unsafe fn apple_ai_get_availability_reason() -> *mut c_char {
+    malloc_string("Unsupported platform")
+}
+
+pub unsafe fn apple_ai_get_availability_reason_code() -> c_int {
+    4
+}
+
+pub unsafe fn apple_ai_prewarm() -> bool {
+    false
+}
+
+pub unsafe fn apple_ai_get_supported_languages_count() -> c_int {
+    0
+}
+
+pub unsafe fn apple_ai_get_supported_language(_index: c_int) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_detect_language(
+    _text: *const c_char,
+    _max_candidates: c_int,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_generate_embedding(_text: *const c_char) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_get_model_name() -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_get_model_version() -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_get_supported_language_tag(_index: c_int) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_last_generation_on_device() -> bool {
+    false
+}
+
+pub unsafe fn apple_ai_get_model_memory_usage() -> i64 {
+    -1
+}
+
+pub unsafe fn apple_ai_supports_feature(_feature: c_int) -> bool {
+    false
+}
+
+pub unsafe fn apple_ai_get_context_window() -> c_int {
+    0
+}
+
+pub unsafe fn apple_ai_count_tokens(_text: *const c_char) -> c_int {
+    0
+}
+
+pub unsafe fn apple_ai_count_tokens_for_messages(_messages_json: *const c_char) -> c_int {
+    -1
+}
+
+pub unsafe fn apple_ai_cancel_request(_handle: u64) {}
+
+pub unsafe fn apple_ai_generate_with_instructions(
+    _instructions: *const c_char,
+    _prompt: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_generate_response_with_history_v2(
+    _messages_json: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_generate_response_stream_v2(
+    _prompt: *const c_char,
+    _options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    on_chunk(stream_id, std::ptr::null());
+}
+
+pub unsafe fn apple_ai_generate_response_with_history_stream(
+    _messages_json: *const c_char,
+    _options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    on_chunk(stream_id, std::ptr::null());
+}
+
+pub unsafe fn apple_ai_cancel_stream(_stream_id: u32) {}
+
+pub unsafe fn apple_ai_session_create(_instructions: *const c_char) -> u32 {
+    0
+}
+
+pub unsafe fn apple_ai_session_respond(
+    _session_id: u32,
+    _prompt: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_session_respond_stream(
+    _session_id: u32,
+    _prompt: *const c_char,
+    _options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    on_chunk(stream_id, std::ptr::null());
+}
+
+pub unsafe fn apple_ai_session_continue(
+    _session_id: u32,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_session_reset(_session_id: u32) -> bool {
+    false
+}
+
+pub unsafe fn apple_ai_session_export(_session_id: u32) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_session_load_history(
+    _session_id: u32,
+    _messages_json: *const c_char,
+) -> bool {
+    false
+}
+
+pub unsafe fn apple_ai_session_free(_session_id: u32) {}
+
+pub unsafe fn apple_ai_generate_structured(
+    _prompt: *const c_char,
+    _schema_json: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_generate_structured_stream(
+    _prompt: *const c_char,
+    _schema_json: *const c_char,
+    _options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    on_chunk(stream_id, std::ptr::null());
+}
+
+pub unsafe fn apple_ai_generate_response_structured(
+    _prompt: *const c_char,
+    _schema_json: *const c_char,
+    _temperature: c_double,
+    _max_tokens: c_int,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_generate_with_tools(
+    _prompt: *const c_char,
+    _tools_json: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_generate_with_tools_stream(
+    _prompt: *const c_char,
+    _tools_json: *const c_char,
+    _options_json: *const c_char,
+    stream_id: u32,
+    on_chunk: extern "C" fn(u32, *const c_char),
+) {
+    on_chunk(stream_id, std::ptr::null());
+}
+
+pub unsafe fn apple_ai_submit_tool_result(_stream_id: u32, _result: *const c_char) -> bool {
+    false
+}
+
+pub unsafe fn apple_ai_generate_with_image(
+    _prompt: *const c_char,
+    _image_data: *const u8,
+    _image_len: usize,
+    _format: *const c_char,
+    _options_json: *const c_char,
+    _handle: u64,
+) -> *mut c_char {
+    std::ptr::null_mut()
+}
+
+pub unsafe fn apple_ai_classify_prompt(_prompt: *const c_char, _handle: u64) -> *mut c_char {
+    std::ptr::null_mut()
+}
@@ -1,5 +1,6 @@
 import Foundation
 import FoundationModels
+import NaturalLanguage
 
 // MARK: - C-compatible data structures
 
@@ -61,6 +62,55 @@ public func appleAIGetAvailabilityReason() -> UnsafeMutablePointer<CChar>? {
     }
 }
 
+@_cdecl("apple_ai_get_availability_reason_code")
+public func appleAIGetAvailabilityReasonCode() -> Int32 {
+    let model = SystemLanguageModel.default
+    let availability = model.availability
+
+    switch availability {
+    case .available:
+        return 0 // Available
+    case .unavailable(let reason):
+        switch reason {
+        case .deviceNotEligible:
+            return 1 // Device not eligible
+        case .appleIntelligenceNotEnabled:
+            return 2 // Apple Intelligence not enabled
+        case .modelNotReady:
+            return 3 // Model not ready
+        @unknown default:
+            return 99 // Unknown reason
+        }
+    @unknown default:
+        return 99 // Unknown reason
+    }
+}
+
+// MARK: - Prewarm
+
+// Session kept alive after prewarming so the loaded model assets stay resident
+private var prewarmedSession: LanguageModelSession?
+private let prewarmLock = NSLock()
+
+@_cdecl("apple_ai_prewarm")
+public func appleAIPrewarm() -> Bool {
+    let model = SystemLanguageModel.default
+    guard case .available = model.availability else {
+        return false
+    }
+
+    prewarmLock.lock()
+    defer { prewarmLock.unlock() }
+
+    // Idempotent: only the first call creates and prewarms the session
+    if prewarmedSession == nil {
+        let session = LanguageModelSession(model: model)
+        session.prewarm()
+        prewarmedSession = session
+    }
+    return true
+}
+
 @_cdecl("apple_ai_get_supported_languages_count")
 public func appleAIGetSupportedLanguagesCount() -> Int32 {
     let model = SystemLanguageModel.default
@@ -92,132 +142,499 @@ public func appleAIGetSupportedLanguage(index: Int32) -> UnsafeMutablePointer<CC
     return strdup("Unknown")
 }
 
-@_cdecl("apple_ai_generate_response")
-public func appleAIGenerateResponse(
-    prompt: UnsafePointer<CChar>,
-    temperature: Double,
-    maxTokens: Int32
+/// BCP-47 tag (`language[-Script][-REGION]`) of the supported language at `index`
+@_cdecl("apple_ai_get_supported_language_tag")
+public func appleAIGetSupportedLanguageTag(index: Int32) -> UnsafeMutablePointer<CChar>? {
+    let languagesArray = Array(SystemLanguageModel.default.supportedLanguages)
+    guard index >= 0 && index < Int32(languagesArray.count) else {
+        return nil
+    }
+
+    let language = languagesArray[Int(index)]
+    guard let languageCode = language.languageCode?.identifier else {
+        return nil
+    }
+    let subtags = [languageCode, language.script?.identifier, language.region?.identifier]
+    return strdup(subtags.compactMap { $0 }.joined(separator: "-"))
+}
+
+// MARK: - Language Detection
+
+/// Up to `maxCandidates` likely languages of `text` as JSON
+/// `[{language, confidence}]`, most likely first. Empty when undetermined.
+@_cdecl("apple_ai_detect_language")
+public func appleAIDetectLanguage(
+    text: UnsafePointer<CChar>,
+    maxCandidates: Int32
+) -> UnsafeMutablePointer<CChar>? {
+    let recognizer = NLLanguageRecognizer()
+    recognizer.processString(String(cString: text))
+    let candidates = recognizer
+        .languageHypotheses(withMaximum: max(Int(maxCandidates), 1))
+        .sorted { $0.value > $1.value }
+        .map { ["language": $0.key.rawValue, "confidence": $0.value] as [String: Any] }
+    guard let data = try? JSONSerialization.data(withJSONObject: candidates),
+          let json = String(data: data, encoding: .utf8) else {
+        return nil
+    }
+    return strdup(json)
+}
+
+// MARK: - Embeddings
+
+/// Sentence embedding of `text` as a JSON array of numbers, from the
+/// NaturalLanguage embedding for its dominant language (English when
+/// undetermined). Nil when the OS has no embedding for that language.
+@_cdecl("apple_ai_generate_embedding")
+public func appleAIGenerateEmbedding(text: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    let string = String(cString: text)
+    let language = NLLanguageRecognizer.dominantLanguage(for: string) ?? .english
+    guard let embedding = NLEmbedding.sentenceEmbedding(for: language),
+          let vector = embedding.vector(for: string),
+          let data = try? JSONSerialization.data(withJSONObject: vector),
+          let json = String(data: data, encoding: .utf8) else {
+        return nil
+    }
+    return strdup(json)
+}
+
+// MARK: - Feature Detection
+
+/// Whether a feature (ids shared with the Rust layer) works right now:
+/// 0 streaming, 1 tool calling, 2 image input. Streaming and tools need the
+/// model to be available; the on-device model takes no images.
+@_cdecl("apple_ai_supports_feature")
+public func appleAISupportsFeature(feature: Int32) -> Bool {
+    guard case .available = SystemLanguageModel.default.availability else {
+        return false
+    }
+    switch feature {
+    case 0, 1: return true
+    default: return false
+    }
+}
+
+// MARK: - Model Info
+
+@_cdecl("apple_ai_get_model_name")
+public func appleAIGetModelName() -> UnsafeMutablePointer<CChar>? {
+    return strdup("apple.SystemLanguageModel.default")
+}
+
+@_cdecl("apple_ai_get_model_version")
+public func appleAIGetModelVersion() -> UnsafeMutablePointer<CChar>? {
+    // FoundationModels doesn't version the model separately; it ships with
+    // the OS, so the OS build identifies it
+    var size = 0
+    guard sysctlbyname("kern.osversion", nil, &size, nil, 0) == 0, size > 0 else {
+        return strdup("")
+    }
+    var build = [CChar](repeating: 0, count: size)
+    guard sysctlbyname("kern.osversion", &build, &size, nil, 0) == 0 else {
+        return strdup("")
+    }
+    return strdup(build)
+}
+
+/// Bytes the loaded model occupies, or -1 when unknown. FoundationModels runs
+/// the model outside this process and reports no memory figure, so it's
+/// always unknown; return the real number here should the OS ever expose it.
+@_cdecl("apple_ai_get_model_memory_usage")
+public func appleAIGetModelMemoryUsage() -> Int64 {
+    return -1
+}
+
+/// Whether the most recent generation ran on this device. FoundationModels
+/// only serves the on-device system model and reports no routing, so this
+/// is always true; should requests ever leave the device, it must say so.
+@_cdecl("apple_ai_last_generation_on_device")
+public func appleAILastGenerationOnDevice() -> Bool {
+    return true
+}
+
+// MARK: - Cancellation Support
+
+// In-flight generations keyed by the opaque handle supplied by the caller
+private var activeRequests: [UInt64: Task<Void, Never>] = [:]
+// Handles cancelled before their task was registered
+private var pendingCancellations: Set<UInt64> = []
+private let requestsLock = NSLock()
+
+private func registerRequest(_ handle: UInt64, task: Task<Void, Never>) {
+    requestsLock.lock()
+    defer { requestsLock.unlock() }
+
+    if pendingCancellations.remove(handle) != nil {
+        task.cancel()
+    } else {
+        activeRequests[handle] = task
+    }
+}
+
+private func unregisterRequest(_ handle: UInt64) {
+    requestsLock.lock()
+    defer { requestsLock.unlock() }
+    activeRequests.removeValue(forKey: handle)
+}
+
+@_cdecl("apple_ai_cancel_request")
+public func appleAICancelRequest(handle: UInt64) {
+    requestsLock.lock()
+    defer { requestsLock.unlock() }
+
+    if let task = activeRequests.removeValue(forKey: handle) {
+        task.cancel()
+    } else {
+        pendingCancellations.insert(handle)
+    }
+}
+
+/// Run `body` on a task registered under `handle`, blocking until it finishes.
+/// Thrown errors become `Error: …` strings; returns NULL when cancelled.
+private func runCancellable(
+    handle: UInt64,
+    _ body: @escaping () async throws -> String
 ) -> UnsafeMutablePointer<CChar>? {
-    let promptString = String(cString: prompt)
-    
     // Use semaphore to convert async to sync
     let semaphore = DispatchSemaphore(value: 0)
-    var result: String = "Error: No response"
-    
-    Task {
+    var result: String? = "Error: No response"
+
+    let task = Task {
+        defer { semaphore.signal() }
         do {
-            let model = SystemLanguageModel.default
-            
-            // Check availability first
-            let availability = model.availability
-            guard case .available = availability else {
-                result = "Error: Apple Intelligence not available"
-                semaphore.signal()
-                return
-            }
-            
-            // Create session
-            let session = LanguageModelSession()
-            
-            // Create generation options
-            var options = GenerationOptions()
-            if temperature > 0 {
-                options = GenerationOptions(temperature: temperature, maximumResponseTokens: maxTokens > 0 ? Int(maxTokens) : nil)
-            } else if maxTokens > 0 {
-                options = GenerationOptions(maximumResponseTokens: Int(maxTokens))
-            }
-            
-            // Generate response
-            let response = try await session.respond(to: promptString, options: options)
-            result = response.content
-            
+            try Task.checkCancellation()
+            let output = try await body()
+            result = Task.isCancelled ? nil : output
         } catch {
-            result = "Error: \(error.localizedDescription)"
+            result = Task.isCancelled ? nil : errorPayload(error)
         }
-        
-        semaphore.signal()
     }
-    
-    // Wait for async operation to complete
+    registerRequest(handle, task: task)
+
+    // Wait for async operation to complete (or be cancelled)
     semaphore.wait()
-    
-    return strdup(result)
+    unregisterRequest(handle)
+
+    return result.flatMap { strdup($0) }
+}
+
+// MARK: - Error Codes
+
+/// Integer error codes shared with the Rust layer's `GenerationErrorCode`
+private enum GenerationErrorCode: Int32 {
+    case unknown = 0
+    case modelUnavailable = 1
+    case guardrailViolation = 2
+    case contextOverflow = 3
+    case unsupportedLanguage = 4
+    case rateLimited = 5
+    case busy = 6
+    case invalidInput = 7
+    case decodingFailure = 8
+    case refusal = 9
+    case multimodalUnsupported = 10
+
+    init(_ error: Error) {
+        if let coded = error as? CodedError {
+            self = coded.code
+            return
+        }
+        if error is DecodingError {
+            self = .invalidInput
+            return
+        }
+        guard let generationError = error as? LanguageModelSession.GenerationError else {
+            self = .unknown
+            return
+        }
+        switch generationError {
+        case .exceededContextWindowSize: self = .contextOverflow
+        case .assetsUnavailable: self = .modelUnavailable
+        case .guardrailViolation: self = .guardrailViolation
+        case .unsupportedGuide: self = .invalidInput
+        case .unsupportedLanguageOrLocale: self = .unsupportedLanguage
+        case .decodingFailure: self = .decodingFailure
+        case .rateLimited: self = .rateLimited
+        case .concurrentRequests: self = .busy
+        case .refusal: self = .refusal
+        @unknown default: self = .unknown
+        }
+    }
+}
+
+/// Failure raised by this layer itself, with an explicit code
+private struct CodedError: Error, LocalizedError {
+    let code: GenerationErrorCode
+    let message: String
+
+    init(_ code: GenerationErrorCode, _ message: String) {
+        self.code = code
+        self.message = message
+    }
+
+    var errorDescription: String? { message }
+}
+
+/// Message reported for `error`. Guardrail blocks get a recognisable message plus
+/// whatever context FoundationModels gives (it doesn't name the violated category).
+private func errorMessage(_ error: Error) -> String {
+    if let generationError = error as? LanguageModelSession.GenerationError,
+       case .guardrailViolation(let context) = generationError {
+        let detail = context.debugDescription
+        return detail.isEmpty
+            ? "Blocked by safety guardrails"
+            : "Blocked by safety guardrails: \(detail)"
+    }
+    return error.localizedDescription
+}
+
+/// Encode `{ code, message }`, the error payload of the `_v2` entry points and streams
+private func errorPayload(_ error: Error) -> String {
+    let json: [String: Any] = [
+        "code": GenerationErrorCode(error).rawValue,
+        "message": errorMessage(error)
+    ]
+    guard let data = try? JSONSerialization.data(withJSONObject: json, options: []),
+          let string = String(data: data, encoding: .utf8) else {
+        return "Error: \(error.localizedDescription)"
+    }
+    return string
+}
+
+// MARK: - Usage Reporting
+
+/// Rough token estimate, since FoundationModels doesn't report usage.
+/// ASCII text averages ~4 characters per token; other scripts ~1 per character.
+private func estimateTokenCount(_ text: String) -> Int {
+    guard !text.isEmpty else { return 0 }
+    var asciiCount = 0
+    var otherCount = 0
+    for scalar in text.unicodeScalars {
+        if scalar.isASCII {
+            asciiCount += 1
+        } else {
+            otherCount += 1
+        }
+    }
+    return max(1, (asciiCount + 3) / 4 + otherCount)
 }
 
-@_cdecl("apple_ai_generate_response_with_history")
-public func appleAIGenerateResponseWithHistory(
+/// Combined prompt + response token capacity of the on-device model.
+/// FoundationModels documents a single 4096-token window shared by input and output.
+private let CONTEXT_WINDOW_TOKENS: Int32 = 4096
+
+@_cdecl("apple_ai_get_context_window")
+public func appleAIGetContextWindow() -> Int32 {
+    CONTEXT_WINDOW_TOKENS
+}
+
+@_cdecl("apple_ai_count_tokens")
+public func appleAICountTokens(text: UnsafePointer<CChar>) -> Int32 {
+    Int32(clamping: estimateTokenCount(String(cString: text)))
+}
+
+/// Token estimate for a chat `messages_json` array, or -1 if it can't be decoded
+@_cdecl("apple_ai_count_tokens_for_messages")
+public func appleAICountTokensForMessages(messagesJson: UnsafePointer<CChar>) -> Int32 {
+    guard let messages = try? decodeMessages(String(cString: messagesJson)) else { return -1 }
+    return Int32(clamping: messages.reduce(0) { $0 + estimateTokenCount($1.content) })
+}
+
+/// Encode `{ text, promptTokens, completionTokens[, toolCall] }` for the `_v2` entry points
+private func usageResultJSON(
+    text: String,
+    promptTokens: Int,
+    toolCall: RequestedToolCall? = nil,
+    hitStopSequence: Bool = false
+) -> String {
+    var json: [String: Any] = [
+        "text": text,
+        "promptTokens": promptTokens,
+        "completionTokens": estimateTokenCount(text)
+    ]
+    // Other finish reasons are derived on the Rust side
+    if hitStopSequence {
+        json["finishReason"] = "stop_sequence"
+    }
+    if let toolCall {
+        json["toolCall"] = ["name": toolCall.name, "arguments": toolCall.arguments]
+    }
+    guard let data = try? JSONSerialization.data(withJSONObject: json, options: []),
+          let string = String(data: data, encoding: .utf8) else {
+        return "Error: Failed to encode response"
+    }
+    return string
+}
+
+// MARK: - Generation Options (v2 entry points)
+
+/// Settings passed as JSON to the `_v2` entry points
+private struct GenerationConfig: Decodable {
+    var temperature: Double?
+    /// 0 or absent: no limit beyond the model's own
+    var maxTokens: Int?
+    var topP: Double?
+    var seed: UInt64?
+    var stopSequences: [String]?
+    /// "json" asks for a bare JSON object; absent for plain text
+    var responseFormat: String?
+    /// Greedy decoding, overriding temperature and top-p
+    var greedy: Bool?
+    /// BCP-47 tag of the language the reply must be in
+    var language: String?
+    /// Streams only: report the final prompt before generating
+    var echoPrompt: Bool?
+
+    static func decode(_ json: String) throws -> GenerationConfig {
+        return try JSONDecoder().decode(GenerationConfig.self, from: Data(json.utf8))
+    }
+
+    func makeOptions() -> GenerationOptions {
+        var options = GenerationOptions()
+        if let temperature, temperature > 0 { options.temperature = temperature }
+        if let maxTokens, maxTokens > 0 { options.maximumResponseTokens = maxTokens }
+        if greedy == true {
+            options.sampling = .greedy
+        } else if topP != nil || seed != nil {
+            // A seed only matters for random sampling; default to the full distribution
+            options.sampling = .random(probabilityThreshold: topP ?? 1.0, seed: seed)
+        }
+        return options
+    }
+
+    /// `prompt` plus whatever the language and response format require
+    func prompt(_ prompt: String) -> String {
+        var prompt = prompt
+        if let language {
+            // GenerationOptions has no locale setting, so the model is told directly
+            let name = Locale(identifier: "en").localizedString(forIdentifier: language) ?? language
+            prompt += "\n\nWrite your entire response in \(name), whatever language the text above is in."
+        }
+        guard responseFormat == "json" else { return prompt }
+        return prompt + "\n\nRespond with a single valid JSON object only, without code fences or any other text."
+    }
+}
+
+/// Position of the earliest stop sequence in `text`
+private func firstStopMatch(in text: String, _ stopSequences: [String]) -> String.Index? {
+    return stopSequences.compactMap { text.range(of: $0)?.lowerBound }.min()
+}
+
+/// Length of the longest tail of `text` that is a proper prefix of a stop sequence,
+/// i.e. output that must be held back until it's known not to start a match
+private func stopPrefixOverlap(_ text: String, _ stopSequences: [String]) -> Int {
+    var longest = 0
+    for stop in stopSequences {
+        var length = min(stop.count - 1, text.count)
+        while length > longest {
+            if text.hasSuffix(String(stop.prefix(length))) {
+                longest = length
+                break
+            }
+            length -= 1
+        }
+    }
+    return longest
+}
+
+/// Respond to `prompt`, halting early (and truncating) at the first stop sequence;
+/// `hitStopSequence` tells whether that happened
+private func respondText(
+    _ session: LanguageModelSession,
+    to prompt: String,
+    config: GenerationConfig
+) async throws -> (text: String, hitStopSequence: Bool) {
+    let stopSequences = config.stopSequences ?? []
+    let prompt = config.prompt(prompt)
+    guard !stopSequences.isEmpty else {
+        return (try await session.respond(to: prompt, options: config.makeOptions()).content, false)
+    }
+
+    // Stream internally so generation stops as soon as a stop sequence shows up
+    var text = ""
+    for try await cumulative in session.streamResponse(to: prompt, options: config.makeOptions()) {
+        text = cumulative
+        if let stop = firstStopMatch(in: text, stopSequences) {
+            return (String(text[..<stop]), true)
+        }
+    }
+    return (text, false)
+}
+
+/// Decode a `messages_json` payload
+private func decodeMessages(_ json: String) throws -> [ChatMessage] {
+    return try JSONDecoder().decode([ChatMessage].self, from: Data(json.utf8))
+}
+
+/// Session whose transcript opens with `instructions` (the system role), if any.
+/// Equivalent to `LanguageModelSession(instructions:)`.
+private func makeSession(model: SystemLanguageModel, instructions: String) -> LanguageModelSession {
+    guard !instructions.isEmpty else {
+        return LanguageModelSession(model: model)
+    }
+    let entries = convertMessagesToTranscript([ChatMessage(role: "system", content: instructions)])
+    return LanguageModelSession(transcript: Transcript(entries: entries))
+}
+
+/// Respond to `prompt` under optional system `instructions` (empty for none)
+@_cdecl("apple_ai_generate_with_instructions")
+public func appleAIGenerateWithInstructions(
+    instructions: UnsafePointer<CChar>,
+    prompt: UnsafePointer<CChar>,
+    optionsJson: UnsafePointer<CChar>,
+    handle: UInt64
+) -> UnsafeMutablePointer<CChar>? {
+    let instructionsString = String(cString: instructions)
+    let promptString = String(cString: prompt)
+    let optionsJsonString = String(cString: optionsJson)
+
+    return runCancellable(handle: handle) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+
+        // Check availability first
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Apple Intelligence not available")
+        }
+
+        let session = makeSession(model: model, instructions: instructionsString)
+        let (text, hitStop) = try await respondText(session, to: promptString, config: config)
+        let promptTokens = estimateTokenCount(instructionsString) + estimateTokenCount(promptString)
+        return usageResultJSON(text: text, promptTokens: promptTokens, hitStopSequence: hitStop)
+    }
+}
+
+@_cdecl("apple_ai_generate_response_with_history_v2")
+public func appleAIGenerateResponseWithHistoryV2(
     messagesJson: UnsafePointer<CChar>,
-    temperature: Double,
-    maxTokens: Int32
+    optionsJson: UnsafePointer<CChar>,
+    handle: UInt64
 ) -> UnsafeMutablePointer<CChar>? {
     let messagesJsonString = String(cString: messagesJson)
-    
-    // Use semaphore to convert async to sync
-    let semaphore = DispatchSemaphore(value: 0)
-    var result: String = "Error: No response"
-    
-    Task {
-        do {
-            let model = SystemLanguageModel.default
-            
-            // Check availability first
-            let availability = model.availability
-            guard case .available = availability else {
-                result = "Error: Apple Intelligence not available"
-                semaphore.signal()
-                return
-            }
-            
-            // Parse messages from JSON
-            guard let messagesData = messagesJsonString.data(using: .utf8) else {
-                result = "Error: Invalid JSON data"
-                semaphore.signal()
-                return
-            }
-            
-            let messages = try JSONDecoder().decode([ChatMessage].self, from: messagesData)
-            
-            guard !messages.isEmpty else {
-                result = "Error: No messages provided"
-                semaphore.signal()
-                return
-            }
-            
-            // Get the last message as the current prompt
-            let lastMessage = messages.last!
-            let currentPrompt = lastMessage.content
-            
-            // Convert previous messages to transcript for conversation context
-            let previousMessages = messages.count > 1 ? Array(messages.dropLast()) : []
-            let transcriptEntries = convertMessagesToTranscript(previousMessages)
-            
-            // Create session with conversation history
-            let transcript = Transcript(entries: transcriptEntries)
-            let session = LanguageModelSession(transcript: transcript)
-            
-            // Create generation options
-            var options = GenerationOptions()
-            if temperature > 0 {
-                options = GenerationOptions(temperature: temperature, maximumResponseTokens: maxTokens > 0 ? Int(maxTokens) : nil)
-            } else if maxTokens > 0 {
-                options = GenerationOptions(maximumResponseTokens: Int(maxTokens))
-            }
-            
-            // Generate response using the current prompt with history
-            let response = try await session.respond(to: currentPrompt, options: options)
-            result = response.content
-            
-        } catch {
-            result = "Error: \(error.localizedDescription)"
+    let optionsJsonString = String(cString: optionsJson)
+
+    return runCancellable(handle: handle) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+
+        // Check availability first
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Apple Intelligence not available")
         }
-        
-        semaphore.signal()
+
+        let messages = try decodeMessages(messagesJsonString)
+        guard let lastMessage = messages.last else {
+            throw CodedError(.invalidInput, "No messages provided")
+        }
+
+        // Previous messages become the session transcript, the last one is the prompt
+        let transcriptEntries = convertMessagesToTranscript(Array(messages.dropLast()))
+        let session = LanguageModelSession(transcript: Transcript(entries: transcriptEntries))
+
+        let (text, hitStop) = try await respondText(session, to: lastMessage.content, config: config)
+        let promptTokens = messages.reduce(0) { $0 + estimateTokenCount($1.content) }
+        return usageResultJSON(text: text, promptTokens: promptTokens, hitStopSequence: hitStop)
     }
-    
-    // Wait for async operation to complete
-    semaphore.wait()
-    
-    return strdup(result)
 }
 
 @_cdecl("apple_ai_free_string")
@@ -241,223 +658,526 @@ private struct ChatMessage: Codable {
     }
 }
 
-private func convertMessagesToTranscript(_ messages: [ChatMessage]) -> [Transcript.Entry] {
-    var entries: [Transcript.Entry] = []
-    
-    // Process all messages in order
-    for message in messages {
-        let textSegment = Transcript.TextSegment(content: message.content)
-        
-        switch message.role.lowercased() {
-        case "system":
-            // Convert system messages to instructions
-            let instructions = Transcript.Instructions(
-                segments: [.text(textSegment)],
-                toolDefinitions: []
-            )
-            entries.append(.instructions(instructions))
-            
-        case "user":
-            // Convert user messages to prompts
-            let prompt = Transcript.Prompt(
-                segments: [.text(textSegment)]
-            )
-            entries.append(.prompt(prompt))
-            
-        case "assistant":
-            // Convert assistant messages to responses
-            let response = Transcript.Response(
-                assetIDs: [],
-                segments: [.text(textSegment)]
-            )
-            entries.append(.response(response))
-            
-        default:
-            // Treat unknown roles as user messages
-            let prompt = Transcript.Prompt(
-                segments: [.text(textSegment)]
-            )
-            entries.append(.prompt(prompt))
+private func convertMessagesToTranscript(_ messages: [ChatMessage]) -> [Transcript.Entry] {
+    var entries: [Transcript.Entry] = []
+    
+    // Process all messages in order
+    for message in messages {
+        // The transcript has no speaker field, so named turns carry the name inline
+        let content = message.name.map { "\($0): \(message.content)" } ?? message.content
+        let textSegment = Transcript.TextSegment(content: content)
+        
+        switch message.role.lowercased() {
+        case "system":
+            // Convert system messages to instructions
+            let instructions = Transcript.Instructions(
+                segments: [.text(Transcript.TextSegment(content: message.content))],
+                toolDefinitions: []
+            )
+            entries.append(.instructions(instructions))
+            
+        case "user":
+            // Convert user messages to prompts
+            let prompt = Transcript.Prompt(
+                segments: [.text(textSegment)]
+            )
+            entries.append(.prompt(prompt))
+            
+        case "assistant":
+            // Convert assistant messages to responses
+            let response = Transcript.Response(
+                assetIDs: [],
+                segments: [.text(textSegment)]
+            )
+            entries.append(.response(response))
+            
+        default:
+            // Treat unknown roles as user messages
+            let prompt = Transcript.Prompt(
+                segments: [.text(textSegment)]
+            )
+            entries.append(.prompt(prompt))
+        }
+    }
+    
+    return entries
+}
+
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_generate_response_stream_v2")
+public func appleAIGenerateResponseStreamV2(
+    _ prompt: UnsafePointer<CChar>,
+    _ optionsJson: UnsafePointer<CChar>,
+    _ streamId: UInt32,
+    _ onChunk: StreamCallback
+) {
+    let promptString = String(cString: prompt)
+    let optionsJsonString = String(cString: optionsJson)
+
+    startStream(streamId, onChunk: onChunk) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Model unavailable")
+        }
+
+        let session = LanguageModelSession(model: model)
+        let finalPrompt = config.prompt(promptString)
+        echoPrompt(finalPrompt, config: config, streamId: streamId, to: onChunk)
+        let stream = session.streamResponse(to: finalPrompt, options: config.makeOptions())
+        try await forwardStream(
+            stream,
+            stopSequences: config.stopSequences ?? [],
+            streamId: streamId,
+            onChunk: onChunk
+        )
+    }
+}
+
+@_cdecl("apple_ai_generate_response_with_history_stream")
+public func appleAIGenerateResponseWithHistoryStream(
+    _ messagesJson: UnsafePointer<CChar>,
+    _ optionsJson: UnsafePointer<CChar>,
+    _ streamId: UInt32,
+    _ onChunk: StreamCallback
+) {
+    let messagesJsonString = String(cString: messagesJson)
+    let optionsJsonString = String(cString: optionsJson)
+
+    startStream(streamId, onChunk: onChunk) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Model unavailable")
+        }
+
+        let messages = try decodeMessages(messagesJsonString)
+        guard let lastMessage = messages.last else {
+            throw CodedError(.invalidInput, "No messages provided")
+        }
+
+        // Previous messages become the session transcript, the last one is the prompt
+        let transcriptEntries = convertMessagesToTranscript(Array(messages.dropLast()))
+        let session = LanguageModelSession(transcript: Transcript(entries: transcriptEntries))
+        let finalPrompt = config.prompt(lastMessage.content)
+        if config.echoPrompt == true {
+            // The messages as the model sees them: the last one with its suffixes
+            let echoed = Array(messages.dropLast()) + [ChatMessage(role: lastMessage.role, content: finalPrompt, name: lastMessage.name)]
+            let json = (try? JSONEncoder().encode(echoed)).flatMap { String(data: $0, encoding: .utf8) } ?? finalPrompt
+            echoPrompt(json, config: config, streamId: streamId, to: onChunk)
+        }
+        let stream = session.streamResponse(to: finalPrompt, options: config.makeOptions())
+        try await forwardStream(
+            stream,
+            stopSequences: config.stopSequences ?? [],
+            streamId: streamId,
+            onChunk: onChunk
+        )
+    }
+}
+
+/// Run `body` on a detached task registered under `streamId` so it can be cancelled
+private func startStream(
+    _ streamId: UInt32,
+    onChunk: StreamCallback,
+    _ body: @escaping () async throws -> Void
+) {
+    // Hold the lock until the task is registered so its cleanup can't run first
+    streamLock.lock()
+    defer { streamLock.unlock() }
+
+    activeStreamTasks[streamId] = Task.detached {
+        defer { unregisterStream(streamId) }
+        do {
+            try await body()
+        } catch {
+            // A cancelled stream has already been torn down on the caller side
+            if Task.isCancelled { return }
+            emitError(error, streamId: streamId, to: onChunk)
+        }
+    }
+}
+
+/// Forward the deltas of a cumulative response stream to `onChunk`, then the end marker.
+/// Output is cut at the first stop sequence, which ends the stream early.
+private func forwardStream<S: AsyncSequence>(
+    _ stream: S,
+    stopSequences: [String],
+    streamId: UInt32,
+    onChunk: StreamCallback
+) async throws where S.Element == String {
+    var latest = ""
+    var sent = 0 // characters of `latest` already delivered
+    var delivered = "" // and what they were
+
+    func deliver(upTo end: Int) {
+        guard end > sent else { return }
+        let delta = String(latest.dropFirst(sent).prefix(end - sent))
+        sent = end
+        delivered += delta
+        guard delta.first != ERROR_SENTINEL, delta.first != FINISH_SENTINEL,
+              delta.first != TOOL_CALL_SENTINEL, delta.first != PROMPT_SENTINEL,
+              delta.first != RESTART_SENTINEL else { return }
+
+        delta.withCString { cStr in
+            onChunk(streamId, strdup(cStr))
+        }
+    }
+
+    for try await cumulative in stream {
+        // Stop producing chunks once the caller cancelled the stream
+        if Task.isCancelled { return }
+        latest = cumulative
+        if !latest.hasPrefix(delivered) {
+            // Snapshots re-serialized from structured output needn't extend
+            // the previous one: start the text over from this one
+            String(RESTART_SENTINEL).withCString { cStr in
+                onChunk(streamId, strdup(cStr))
+            }
+            sent = 0
+            delivered = ""
+        }
+
+        if let stop = firstStopMatch(in: latest, stopSequences) {
+            deliver(upTo: latest.distance(from: latest.startIndex, to: stop))
+            emitFinishReason("stop_sequence", streamId: streamId, to: onChunk)
+            onChunk(streamId, nil)    // stopped at a stop sequence
+            return
+        }
+        // Hold back a tail that could still grow into a stop sequence
+        deliver(upTo: latest.count - stopPrefixOverlap(latest, stopSequences))
+    }
+    if Task.isCancelled { return }
+    deliver(upTo: latest.count)
+    onChunk(streamId, nil)        // stream finished
+}
+
+/// Chunk callback shared by all streaming entry points: (stream id, chunk or NULL at end)
+public typealias StreamCallback = @convention(c) (UInt32, UnsafePointer<CChar>?) -> Void
+
+// Tasks driving the active streams, kept so they can be cancelled early
+private var activeStreamTasks: [UInt32: Task<Void, Never>] = [:]
+private let streamLock = NSLock()
+
+private func unregisterStream(_ streamId: UInt32) {
+    streamLock.lock()
+    defer { streamLock.unlock() }
+    activeStreamTasks.removeValue(forKey: streamId)
+}
+
+@_cdecl("apple_ai_cancel_stream")
+public func appleAICancelStream(_ streamId: UInt32) {
+    streamLock.lock()
+    let task = activeStreamTasks.removeValue(forKey: streamId)
+    streamLock.unlock()
+
+    task?.cancel()
+    failPendingToolResults(streamId)
+}
+
+// Control-B (0x02) sentinel prefix marks an error string in streaming callbacks
+private let ERROR_SENTINEL: Character = "\u{0002}"
+
+@inline(__always)
+private func emitError(_ error: Error, streamId: UInt32, to onChunk: StreamCallback) {
+    let full = String(ERROR_SENTINEL) + errorPayload(error)
+    full.withCString { cStr in
+        onChunk(streamId, strdup(cStr))
+    }
+}
+
+// Control-C (0x03) sentinel prefix names the finish reason, ahead of the end marker
+private let FINISH_SENTINEL: Character = "\u{0003}"
+
+private func emitFinishReason(_ reason: String, streamId: UInt32, to onChunk: StreamCallback) {
+    (String(FINISH_SENTINEL) + reason).withCString { cStr in
+        onChunk(streamId, strdup(cStr))
+    }
+}
+
+// Control-E (0x05) sentinel prefix carries the prompt as handed to the model
+private let PROMPT_SENTINEL: Character = "\u{0005}"
+
+// Control-F (0x06) sentinel: the text delivered so far is void, and the chunks
+// that follow restart it
+private let RESTART_SENTINEL: Character = "\u{0006}"
+
+/// Report `prompt` first on the stream if the caller asked for `echoPrompt`
+private func echoPrompt(_ prompt: String, config: GenerationConfig, streamId: UInt32, to onChunk: StreamCallback) {
+    guard config.echoPrompt == true else { return }
+    (String(PROMPT_SENTINEL) + prompt).withCString { cStr in
+        onChunk(streamId, strdup(cStr))
+    }
+}
+
+// MARK: - Persistent Sessions
+
+// Sessions owned by JS `Session` objects; each keeps its transcript across requests
+private var sessions: [UInt32: LanguageModelSession] = [:]
+// Instructions each session was created with (NULL ones omitted), for `apple_ai_session_reset`
+private var sessionInstructions: [UInt32: String] = [:]
+// Speaker of each named turn loaded by `apple_ai_session_load_history`, by transcript
+// entry id: the transcript itself only carries the name inline
+private var sessionTurnNames: [UInt32: [String: String]] = [:]
+private var nextSessionId: UInt32 = 1
+private let sessionsLock = NSLock()
+
+private func lookupSession(_ sessionId: UInt32) -> LanguageModelSession? {
+    sessionsLock.lock()
+    defer { sessionsLock.unlock() }
+    return sessions[sessionId]
+}
+
+/// Create a session with optional instructions (may be NULL). Returns 0 if the model is unavailable.
+@_cdecl("apple_ai_session_create")
+public func appleAISessionCreate(instructions: UnsafePointer<CChar>?) -> UInt32 {
+    let model = SystemLanguageModel.default
+    guard case .available = model.availability else { return 0 }
+
+    let session: LanguageModelSession
+    if let instructions = instructions {
+        session = makeSession(model: model, instructions: String(cString: instructions))
+    } else {
+        session = LanguageModelSession(model: model)
+    }
+
+    sessionsLock.lock()
+    defer { sessionsLock.unlock() }
+    let sessionId = nextSessionId
+    nextSessionId &+= 1
+    sessions[sessionId] = session
+    if let instructions = instructions {
+        sessionInstructions[sessionId] = String(cString: instructions)
+    }
+    return sessionId
+}
+
+@_cdecl("apple_ai_session_respond")
+public func appleAISessionRespond(
+    sessionId: UInt32,
+    prompt: UnsafePointer<CChar>,
+    optionsJson: UnsafePointer<CChar>,
+    handle: UInt64
+) -> UnsafeMutablePointer<CChar>? {
+    let promptString = String(cString: prompt)
+    let optionsJsonString = String(cString: optionsJson)
+
+    return runCancellable(handle: handle) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        guard let session = lookupSession(sessionId) else {
+            throw CodedError(.invalidInput, "Unknown session \(sessionId)")
         }
+
+        let (text, hitStop) = try await respondText(session, to: promptString, config: config)
+        return usageResultJSON(
+            text: text,
+            promptTokens: estimateTokenCount(promptString),
+            hitStopSequence: hitStop
+        )
     }
-    
-    return entries
 }
 
-@available(macOS 26.0, *)
-@_cdecl("apple_ai_generate_response_stream")
-public func appleAIGenerateResponseStream(
+@_cdecl("apple_ai_session_respond_stream")
+public func appleAISessionRespondStream(
+    _ sessionId: UInt32,
     _ prompt: UnsafePointer<CChar>,
-    _ temperature: Double,
-    _ maxTokens: Int32,
-    _ onChunk: (@convention(c) (UnsafePointer<CChar>?) -> Void)
+    _ optionsJson: UnsafePointer<CChar>,
+    _ streamId: UInt32,
+    _ onChunk: StreamCallback
 ) {
     let promptString = String(cString: prompt)
+    let optionsJsonString = String(cString: optionsJson)
 
-    Task.detached {
-        do {
-            let model = SystemLanguageModel.default
-            guard case .available = model.availability else {
-                emitError("Model unavailable", to: onChunk)
-                return
-            }
+    startStream(streamId, onChunk: onChunk) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        guard let session = lookupSession(sessionId) else {
+            throw CodedError(.invalidInput, "Unknown session \(sessionId)")
+        }
 
-            var options = GenerationOptions()
-            if temperature != 0.0 { options.temperature = temperature }
-            if maxTokens > 0 { options.maximumResponseTokens = Int(maxTokens) }
+        let finalPrompt = config.prompt(promptString)
+        if config.echoPrompt == true {
+            sessionsLock.lock()
+            let instructions = sessionInstructions[sessionId]
+            sessionsLock.unlock()
+            let echoed = instructions.map { $0 + "\n\n" + finalPrompt } ?? finalPrompt
+            echoPrompt(echoed, config: config, streamId: streamId, to: onChunk)
+        }
+        let stream = session.streamResponse(to: finalPrompt, options: config.makeOptions())
+        try await forwardStream(
+            stream,
+            stopSequences: config.stopSequences ?? [],
+            streamId: streamId,
+            onChunk: onChunk
+        )
+    }
+}
 
-            let session = LanguageModelSession(model: model)
+// Turn asking the model to carry on with its previous, cut-off response
+private let continuationPrompt =
+    "Continue your previous response exactly where it stopped. Don't repeat any of it or add an introduction."
 
-            var prev = ""
-            for try await cumulative in session.streamResponse(to: promptString, options: options) {
-                let delta = String(cumulative.dropFirst(prev.count))
-                prev = cumulative
-                guard !delta.isEmpty, delta.first != ERROR_SENTINEL else { continue }
+/// Generate more of a session's last response, e.g. after it stopped at `maxTokens`.
+/// FoundationModels can't resume decoding, so this is a new turn asking the model to go on,
+/// with the cut-off response still in the transcript.
+@_cdecl("apple_ai_session_continue")
+public func appleAISessionContinue(
+    sessionId: UInt32,
+    optionsJson: UnsafePointer<CChar>,
+    handle: UInt64
+) -> UnsafeMutablePointer<CChar>? {
+    let optionsJsonString = String(cString: optionsJson)
 
-                delta.withCString { cStr in
-                    onChunk(strdup(cStr))
-                }
-            }
-            onChunk(nil)        // stream finished
-        } catch {
-            emitError(error.localizedDescription, to: onChunk)
+    return runCancellable(handle: handle) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        guard let session = lookupSession(sessionId) else {
+            throw CodedError(.invalidInput, "Unknown session \(sessionId)")
         }
+
+        let (text, hitStop) = try await respondText(session, to: continuationPrompt, config: config)
+        return usageResultJSON(
+            text: text,
+            promptTokens: estimateTokenCount(continuationPrompt),
+            hitStopSequence: hitStop
+        )
     }
 }
 
-// Control-B (0x02) sentinel prefix marks an error string in streaming callbacks
-private let ERROR_SENTINEL: Character = "\u{0002}"
+/// Swap a session for a fresh one with the same instructions, dropping its transcript.
+/// The model stays loaded. Returns false for an unknown session.
+@_cdecl("apple_ai_session_reset")
+public func appleAISessionReset(_ sessionId: UInt32) -> Bool {
+    sessionsLock.lock()
+    defer { sessionsLock.unlock() }
+    guard sessions[sessionId] != nil else { return false }
 
-@inline(__always)
-private func emitError(_ message: String, to onChunk: (@convention(c) (UnsafePointer<CChar>?) -> Void)) {
-    let full = String(ERROR_SENTINEL) + message
-    full.withCString { cStr in
-        onChunk(strdup(cStr))
+    let model = SystemLanguageModel.default
+    let session: LanguageModelSession
+    if let instructions = sessionInstructions[sessionId] {
+        session = makeSession(model: model, instructions: instructions)
+    } else {
+        session = LanguageModelSession(model: model)
     }
+    session.prewarm()
+    sessions[sessionId] = session
+    sessionTurnNames.removeValue(forKey: sessionId)
+    return true
 }
 
-// MARK: - Tool Calling Support
+/// Text of a transcript entry's text segments
+@available(macOS 26.0, *)
+private func segmentText(_ segments: [Transcript.Segment]) -> String {
+    return segments.compactMap { segment in
+        if case .text(let text) = segment { return text.content }
+        return nil
+    }.joined()
+}
+
+/// A transcript turn as a ChatMessage, taking its inline `name: ` prefix back
+/// out into `name`
+private func exportedTurn(role: String, content: String, name: String?) -> ChatMessage {
+    guard let name, content.hasPrefix("\(name): ") else {
+        return ChatMessage(role: role, content: content)
+    }
+    return ChatMessage(role: role, content: String(content.dropFirst("\(name): ".count)), name: name)
+}
 
+/// A session's transcript as ChatMessage JSON: instructions become `system`,
+/// prompts `user` and responses `assistant`, with the `name` they were loaded
+/// with. Tool calls and their output aren't conversation turns and are left
+/// out. NULL for an unknown session.
 @available(macOS 26.0, *)
-@_cdecl("apple_ai_generate_response_with_tools")
-public func appleAIGenerateResponseWithTools(
-    messagesJson: UnsafePointer<CChar>,
-    toolsJson: UnsafePointer<CChar>,
-    temperature: Double,
-    maxTokens: Int32
-) -> UnsafeMutablePointer<CChar>? {
-    let messagesJsonString = String(cString: messagesJson)
-    let toolsJsonString = String(cString: toolsJson)
-    
-    // Use semaphore to convert async to sync
-    let semaphore = DispatchSemaphore(value: 0)
-    var result: String = "Error: No response"
-    
-    Task {
-        do {
-            let model = SystemLanguageModel.default
-            
-            // Check availability first
-            let availability = model.availability
-            guard case .available = availability else {
-                result = "Error: Apple Intelligence not available"
-                semaphore.signal()
-                return
-            }
-            
-            // Parse messages from JSON
-            guard let messagesData = messagesJsonString.data(using: .utf8) else {
-                result = "Error: Invalid messages JSON data"
-                semaphore.signal()
-                return
-            }
-            
-            let messages = try JSONDecoder().decode([ChatMessage].self, from: messagesData)
-            
-            // Parse tools from JSON
-            guard let toolsData = toolsJsonString.data(using: .utf8) else {
-                result = "Error: Invalid tools JSON data"
-                semaphore.signal()
-                return
-            }
-            
-            let toolDefinitions = try JSONDecoder().decode([ToolDefinition].self, from: toolsData)
-            
-            // Get the last message as the current prompt
-            let lastMessage = messages.last!
-            let currentPrompt = lastMessage.content
-            
-            // Convert previous messages to transcript for conversation context
-            let previousMessages = messages.count > 1 ? Array(messages.dropLast()) : []
-            let transcriptEntries = convertMessagesToTranscript(previousMessages)
-            
-            // Create instructions with tool definitions
-            var instructionSegments: [Transcript.Segment] = []
-            if !toolDefinitions.isEmpty {
-                // Create tool definitions compatible with Apple's API
-                var toolDescriptions = "You have access to the following tools:\n\n"
-                for tool in toolDefinitions {
-                    toolDescriptions += "Tool: \(tool.name)\n"
-                    if let desc = tool.description {
-                        toolDescriptions += "Description: \(desc)\n"
-                    }
-                    toolDescriptions += "\n"
-                }
-                
-                let textSegment = Transcript.TextSegment(content: toolDescriptions)
-                instructionSegments.append(.text(textSegment))
-            }
-            
-            // Add tool definitions to transcript if tools are provided
-            var finalTranscriptEntries = transcriptEntries
-            if !toolDefinitions.isEmpty {
-                // For now, we'll just add instructions about tools
-                // In a real implementation, we'd need to properly integrate with Apple's Tool API
-                let instructions = Transcript.Instructions(
-                    segments: instructionSegments,
-                    toolDefinitions: [] // Empty for now - requires proper Tool objects
-                )
-                finalTranscriptEntries.insert(.instructions(instructions), at: 0)
-            }
-            
-            // Create session with conversation history
-            let transcript = Transcript(entries: finalTranscriptEntries)
-            let session = LanguageModelSession(transcript: transcript)
-            
-            // Create generation options
-            var options = GenerationOptions()
-            if temperature > 0 {
-                options = GenerationOptions(temperature: temperature, maximumResponseTokens: maxTokens > 0 ? Int(maxTokens) : nil)
-            } else if maxTokens > 0 {
-                options = GenerationOptions(maximumResponseTokens: Int(maxTokens))
-            }
-            
-            // Generate response - for now without native tool support
-            let response = try await session.respond(to: currentPrompt, options: options)
-            
-            // Create response JSON
-            var responseDict: [String: Any] = [:]
-            responseDict["text"] = response.content
-            responseDict["toolCalls"] = [] // Empty for now
-            
-            // Convert to JSON string
-            let jsonData = try JSONSerialization.data(withJSONObject: responseDict, options: [])
-            result = String(data: jsonData, encoding: .utf8) ?? "Error: Failed to encode response"
-            
-        } catch {
-            result = "Error: \(error.localizedDescription)"
+@_cdecl("apple_ai_session_export")
+public func appleAISessionExport(_ sessionId: UInt32) -> UnsafeMutablePointer<CChar>? {
+    guard let session = lookupSession(sessionId) else { return nil }
+    sessionsLock.lock()
+    let names = sessionTurnNames[sessionId] ?? [:]
+    sessionsLock.unlock()
+
+    let messages: [ChatMessage] = session.transcript.compactMap { entry in
+        switch entry {
+        case .instructions(let instructions):
+            return ChatMessage(role: "system", content: segmentText(instructions.segments))
+        case .prompt(let prompt):
+            return exportedTurn(role: "user", content: segmentText(prompt.segments), name: names[entry.id])
+        case .response(let response):
+            return exportedTurn(role: "assistant", content: segmentText(response.segments), name: names[entry.id])
+        default:
+            return nil
         }
-        
-        semaphore.signal()
     }
-    
-    // Wait for async operation to complete
-    semaphore.wait()
-    
-    return strdup(result)
+    guard let data = try? JSONEncoder().encode(messages) else { return nil }
+    return strdup(String(decoding: data, as: UTF8.self))
+}
+
+/// Swap a session for one whose transcript is `messagesJson` (ChatMessage JSON).
+/// Its `system` messages become the instructions `apple_ai_session_reset` keeps.
+/// Returns false for an unknown session or undecodable messages.
+@_cdecl("apple_ai_session_load_history")
+public func appleAISessionLoadHistory(
+    _ sessionId: UInt32,
+    _ messagesJson: UnsafePointer<CChar>
+) -> Bool {
+    guard let messages = try? decodeMessages(String(cString: messagesJson)) else { return false }
+
+    sessionsLock.lock()
+    defer { sessionsLock.unlock() }
+    guard sessions[sessionId] != nil else { return false }
+
+    let entries = convertMessagesToTranscript(messages)
+    let session = LanguageModelSession(transcript: Transcript(entries: entries))
+    session.prewarm()
+    sessions[sessionId] = session
+    // One entry per message, in order
+    var names: [String: String] = [:]
+    for (message, entry) in zip(messages, entries) {
+        if let name = message.name { names[entry.id] = name }
+    }
+    sessionTurnNames[sessionId] = names.isEmpty ? nil : names
+    let instructions = messages.filter { $0.role.lowercased() == "system" }.map(\.content)
+    sessionInstructions[sessionId] = instructions.isEmpty ? nil : instructions.joined(separator: "\n\n")
+    return true
+}
+
+/// Release a session. In-flight requests keep their own reference and finish normally.
+@_cdecl("apple_ai_session_free")
+public func appleAISessionFree(_ sessionId: UInt32) {
+    sessionsLock.lock()
+    defer { sessionsLock.unlock() }
+    sessions.removeValue(forKey: sessionId)
+    sessionInstructions.removeValue(forKey: sessionId)
+    sessionTurnNames.removeValue(forKey: sessionId)
+}
+
+// MARK: - Shutdown
+
+/// Cancel everything in flight and drop the sessions (prewarmed and persistent)
+/// so the model's memory can be reclaimed
+@_cdecl("apple_ai_shutdown")
+public func appleAIShutdown() {
+    streamLock.lock()
+    let streamTasks = Array(activeStreamTasks.values)
+    activeStreamTasks.removeAll()
+    streamLock.unlock()
+    streamTasks.forEach { $0.cancel() }
+
+    requestsLock.lock()
+    let requestTasks = Array(activeRequests.values)
+    activeRequests.removeAll()
+    requestsLock.unlock()
+    requestTasks.forEach { $0.cancel() }
+
+    sessionsLock.lock()
+    sessions.removeAll()
+    sessionInstructions.removeAll()
+    sessionTurnNames.removeAll()
+    sessionsLock.unlock()
+
+    prewarmLock.lock()
+    prewarmedSession = nil
+    prewarmLock.unlock()
 }
 
-// MARK: - Tool Definition Structure
+// MARK: - Tool Calling Support
 
 private struct ToolDefinition: Codable {
     let name: String
@@ -632,6 +1352,96 @@ public func appleAIGenerateResponseStructured(
 }
 
 @available(macOS 26.0, *)
+/// Guided generation against a JSON Schema. The usage JSON's `text` is the generated
+/// object serialized as JSON, so it always conforms to the schema.
+@_cdecl("apple_ai_generate_structured")
+public func appleAIGenerateStructured(
+    prompt: UnsafePointer<CChar>,
+    schemaJson: UnsafePointer<CChar>,
+    optionsJson: UnsafePointer<CChar>,
+    handle: UInt64
+) -> UnsafeMutablePointer<CChar>? {
+    let promptString = String(cString: prompt)
+    let schemaJsonString = String(cString: schemaJson)
+    let optionsJsonString = String(cString: optionsJson)
+
+    return runCancellable(handle: handle) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Apple Intelligence not available")
+        }
+
+        guard let data = schemaJsonString.data(using: .utf8),
+              let jsonObj = try? JSONSerialization.jsonObject(with: data) as? [String: Any] else {
+            throw CodedError(.invalidInput, "Invalid JSON Schema")
+        }
+        let (rootSchema, deps) = buildSchemasFromJson(jsonObj)
+        let generationSchema = try GenerationSchema(root: rootSchema, dependencies: deps)
+
+        let session = LanguageModelSession(model: model)
+        let response = try await session.respond(
+            to: config.prompt(promptString),
+            schema: generationSchema,
+            includeSchemaInPrompt: true,
+            options: config.makeOptions()
+        )
+
+        let object = generatedContentToJSON(response.content)
+        let objectData = try JSONSerialization.data(withJSONObject: object, options: [.fragmentsAllowed])
+        guard let text = String(data: objectData, encoding: .utf8) else {
+            throw CodedError(.unknown, "Encoding failure")
+        }
+        return usageResultJSON(text: text, promptTokens: estimateTokenCount(promptString))
+    }
+}
+
+/// Stream an object conforming to `schemaJson` as the growing JSON text of its
+/// partial snapshots, through the same chunk protocol as text streams
+@_cdecl("apple_ai_generate_structured_stream")
+public func appleAIGenerateStructuredStream(
+    prompt: UnsafePointer<CChar>,
+    schemaJson: UnsafePointer<CChar>,
+    optionsJson: UnsafePointer<CChar>,
+    streamId: UInt32,
+    onChunk: StreamCallback
+) {
+    let promptString = String(cString: prompt)
+    let schemaJsonString = String(cString: schemaJson)
+    let optionsJsonString = String(cString: optionsJson)
+
+    startStream(streamId, onChunk: onChunk) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Model unavailable")
+        }
+
+        guard let data = schemaJsonString.data(using: .utf8),
+              let jsonObj = try? JSONSerialization.jsonObject(with: data) as? [String: Any] else {
+            throw CodedError(.invalidInput, "Invalid JSON Schema")
+        }
+        let (rootSchema, deps) = buildSchemasFromJson(jsonObj)
+        let generationSchema = try GenerationSchema(root: rootSchema, dependencies: deps)
+
+        let session = LanguageModelSession(model: model)
+        let finalPrompt = config.prompt(promptString)
+        echoPrompt(finalPrompt, config: config, streamId: streamId, to: onChunk)
+        let stream = session.streamResponse(
+            to: finalPrompt,
+            schema: generationSchema,
+            includeSchemaInPrompt: true,
+            options: config.makeOptions()
+        )
+        try await forwardStream(
+            stream.map { $0.rawContent.jsonString },
+            stopSequences: [],
+            streamId: streamId,
+            onChunk: onChunk
+        )
+    }
+}
+
 private func convertJSONSchemaToDynamic(_ dict: [String: Any], name: String? = nil) -> DynamicGenerationSchema {
     // Handle references (not fully implemented)
     if let ref = dict["$ref"] as? String {
@@ -760,4 +1570,254 @@ private func buildSchemasFromJson(_ json: [String: Any]) -> (DynamicGenerationSc
     // Fallback
     let root = convertJSONSchemaToDynamic(json, name: json["title"] as? String)
     return (root, dependencies)
-} 
\ No newline at end of file
+} 
+// MARK: - Tool Calling (v2)
+
+/// Tool call requested by the model, handed back to the caller to fulfil
+private struct RequestedToolCall: Error {
+    let name: String
+    /// Arguments object as JSON
+    let arguments: String
+}
+
+/// Tool that runs nothing: calling it ends generation by throwing the call
+/// as a `RequestedToolCall`
+@available(macOS 26.0, *)
+private struct DeferredTool: Tool {
+    typealias Arguments = GeneratedContent
+
+    let name: String
+    let description: String
+    let parameters: GenerationSchema
+
+    func call(arguments: GeneratedContent) async throws -> String {
+        let object = generatedContentToJSON(arguments)
+        let data = try JSONSerialization.data(withJSONObject: object, options: [.fragmentsAllowed])
+        throw RequestedToolCall(name: name, arguments: String(decoding: data, as: UTF8.self))
+    }
+}
+
+/// Respond to `prompt` with `tools_json` (`[{name, description, parameters}]`,
+/// parameters being a JSON Schema) available. A tool call ends generation: the
+/// usage JSON then has empty `text` and a `toolCall`.
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_generate_with_tools")
+public func appleAIGenerateWithTools(
+    prompt: UnsafePointer<CChar>,
+    toolsJson: UnsafePointer<CChar>,
+    optionsJson: UnsafePointer<CChar>,
+    handle: UInt64
+) -> UnsafeMutablePointer<CChar>? {
+    let promptString = String(cString: prompt)
+    let toolsJsonString = String(cString: toolsJson)
+    let optionsJsonString = String(cString: optionsJson)
+
+    return runCancellable(handle: handle) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Apple Intelligence not available")
+        }
+
+        let definitions = try JSONDecoder().decode([ToolDefinition].self, from: Data(toolsJsonString.utf8))
+        let tools: [any Tool] = try definitions.map { definition in
+            let (root, deps) = buildSchemasFromJson(definition.parameters ?? ["type": "object"])
+            return DeferredTool(
+                name: definition.name,
+                description: definition.description ?? "",
+                parameters: try GenerationSchema(root: root, dependencies: deps)
+            )
+        }
+
+        let session = LanguageModelSession(model: model, tools: tools)
+        let promptTokens = estimateTokenCount(promptString)
+        do {
+            let response = try await session.respond(to: config.prompt(promptString), options: config.makeOptions())
+            return usageResultJSON(text: response.content, promptTokens: promptTokens)
+        } catch let error as LanguageModelSession.ToolCallError {
+            guard let call = error.underlyingError as? RequestedToolCall else { throw error }
+            return usageResultJSON(text: "", promptTokens: promptTokens, toolCall: call)
+        }
+    }
+}
+
+// MARK: - Tool Calling (streaming)
+
+// Control-D (0x04) sentinel prefix carries a tool call as JSON `{name, arguments}`
+private let TOOL_CALL_SENTINEL: Character = "\u{0004}"
+
+// Tool calls of the active streams waiting on `apple_ai_submit_tool_result`, oldest first
+private var pendingToolResults: [UInt32: [CheckedContinuation<String, Error>]] = [:]
+private let toolResultsLock = NSLock()
+
+/// Tool that reports its call on the stream, then waits for the caller to
+/// hand back the result
+@available(macOS 26.0, *)
+private struct StreamedTool: Tool {
+    typealias Arguments = GeneratedContent
+
+    let name: String
+    let description: String
+    let parameters: GenerationSchema
+    let streamId: UInt32
+    let onChunk: StreamCallback
+
+    func call(arguments: GeneratedContent) async throws -> String {
+        let object = generatedContentToJSON(arguments)
+        let data = try JSONSerialization.data(withJSONObject: object, options: [.fragmentsAllowed])
+        let call = ["name": name, "arguments": String(decoding: data, as: UTF8.self)]
+        let payload = String(decoding: try JSONSerialization.data(withJSONObject: call), as: UTF8.self)
+
+        return try await withCheckedThrowingContinuation { continuation in
+            toolResultsLock.lock()
+            // Checked under the lock so a concurrent cancel can't miss this continuation
+            if Task.isCancelled {
+                toolResultsLock.unlock()
+                continuation.resume(throwing: CancellationError())
+                return
+            }
+            pendingToolResults[streamId, default: []].append(continuation)
+            toolResultsLock.unlock()
+
+            // Reported only once registered, so the result can't arrive first
+            (String(TOOL_CALL_SENTINEL) + payload).withCString { cStr in
+                onChunk(streamId, strdup(cStr))
+            }
+        }
+    }
+}
+
+/// Stream a response to `prompt` with `tools_json` available (as for
+/// `apple_ai_generate_with_tools`). A tool call is reported on the stream and
+/// pauses it until `apple_ai_submit_tool_result` supplies the output.
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_generate_with_tools_stream")
+public func appleAIGenerateWithToolsStream(
+    _ prompt: UnsafePointer<CChar>,
+    _ toolsJson: UnsafePointer<CChar>,
+    _ optionsJson: UnsafePointer<CChar>,
+    _ streamId: UInt32,
+    _ onChunk: StreamCallback
+) {
+    let promptString = String(cString: prompt)
+    let toolsJsonString = String(cString: toolsJson)
+    let optionsJsonString = String(cString: optionsJson)
+
+    startStream(streamId, onChunk: onChunk) {
+        let config = try GenerationConfig.decode(optionsJsonString)
+        let model = SystemLanguageModel.default
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Model unavailable")
+        }
+
+        let definitions = try JSONDecoder().decode([ToolDefinition].self, from: Data(toolsJsonString.utf8))
+        let tools: [any Tool] = try definitions.map { definition in
+            let (root, deps) = buildSchemasFromJson(definition.parameters ?? ["type": "object"])
+            return StreamedTool(
+                name: definition.name,
+                description: definition.description ?? "",
+                parameters: try GenerationSchema(root: root, dependencies: deps),
+                streamId: streamId,
+                onChunk: onChunk
+            )
+        }
+
+        let session = LanguageModelSession(model: model, tools: tools)
+        let finalPrompt = config.prompt(promptString)
+        echoPrompt(finalPrompt, config: config, streamId: streamId, to: onChunk)
+        let stream = session.streamResponse(to: finalPrompt, options: config.makeOptions())
+        try await forwardStream(
+            stream,
+            stopSequences: config.stopSequences ?? [],
+            streamId: streamId,
+            onChunk: onChunk
+        )
+    }
+}
+
+/// Resume the oldest tool call `streamId` is waiting on with `result`.
+/// False when it isn't waiting on any.
+@_cdecl("apple_ai_submit_tool_result")
+public func appleAISubmitToolResult(_ streamId: UInt32, _ result: UnsafePointer<CChar>) -> Bool {
+    let resultString = String(cString: result)
+
+    toolResultsLock.lock()
+    var continuation: CheckedContinuation<String, Error>?
+    if var waiting = pendingToolResults[streamId], !waiting.isEmpty {
+        continuation = waiting.removeFirst()
+        pendingToolResults[streamId] = waiting.isEmpty ? nil : waiting
+    }
+    toolResultsLock.unlock()
+
+    guard let continuation else { return false }
+    continuation.resume(returning: resultString)
+    return true
+}
+
+/// Fail the tool calls a cancelled stream was waiting on, ending its task
+private func failPendingToolResults(_ streamId: UInt32) {
+    toolResultsLock.lock()
+    let waiting = pendingToolResults.removeValue(forKey: streamId) ?? []
+    toolResultsLock.unlock()
+
+    for continuation in waiting {
+        continuation.resume(throwing: CancellationError())
+    }
+}
+
+// MARK: - Safety
+
+/// `{flagged, categories[, reason]}` for `prompt`, from Apple's guardrails.
+/// FoundationModels has no standalone classifier, so this runs a greedy
+/// one-token generation and checks whether the guardrails blocked it. The
+/// violated category isn't reported, so `categories` stays empty.
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_classify_prompt")
+public func appleAIClassifyPrompt(
+    prompt: UnsafePointer<CChar>,
+    handle: UInt64
+) -> UnsafeMutablePointer<CChar>? {
+    let promptString = String(cString: prompt)
+
+    return runCancellable(handle: handle) {
+        let model = SystemLanguageModel.default
+        guard case .available = model.availability else {
+            throw CodedError(.modelUnavailable, "Apple Intelligence not available")
+        }
+
+        var verdict: [String: Any] = ["flagged": false, "categories": [String]()]
+        do {
+            let session = LanguageModelSession(model: model)
+            let options = GenerationOptions(sampling: .greedy, maximumResponseTokens: 1)
+            _ = try await session.respond(to: promptString, options: options)
+        } catch let error as LanguageModelSession.GenerationError {
+            guard case .guardrailViolation = error else { throw error }
+            verdict["flagged"] = true
+            verdict["reason"] = errorMessage(error)
+        }
+        let data = try JSONSerialization.data(withJSONObject: verdict)
+        return String(decoding: data, as: UTF8.self)
+    }
+}
+
+// MARK: - Image Input
+
+/// Respond to `prompt` about an encoded image (`format` is `png` or `jpeg`).
+/// The on-device model only takes text prompts, so this reports
+/// `multimodalUnsupported` until FoundationModels accepts images.
+@_cdecl("apple_ai_generate_with_image")
+public func appleAIGenerateWithImage(
+    prompt: UnsafePointer<CChar>,
+    imageData: UnsafePointer<UInt8>,
+    imageLength: Int,
+    format: UnsafePointer<CChar>,
+    optionsJson: UnsafePointer<CChar>,
+    handle: UInt64
+) -> UnsafeMutablePointer<CChar>? {
+    let optionsJsonString = String(cString: optionsJson)
+
+    return runCancellable(handle: handle) {
+        _ = try GenerationConfig.decode(optionsJsonString)
+        throw CodedError(.multimodalUnsupported, "The on-device model doesn't accept image input")
+    }
+}
@@ -13,16 +13,116 @@ export interface ChatMessage {
 }
 
 export interface GenerationOptions {
+  /** Sampling temperature in [0, 2]; defaults to 0 */
   temperature?: number;
+  /** Cap on generated tokens; 0 or omitted means no limit */
   maxTokens?: number;
+  /** Nucleus sampling threshold in [0, 1] */
+  topP?: number;
+  /** Sampler seed for reproducible output (same OS/model version only) */
+  seed?: number;
+  /** Generation halts (excluding the match) at the first of these */
+  stopSequences?: string[];
+  /** Reject with a `Timeout` error after this many milliseconds */
+  timeoutMs?: number;
+  /** Streams only: fail with a `Timeout` error when no chunk arrives for this long */
+  idleTimeoutMs?: number;
+  /** Streams only: pause generation while this many chunks wait for a slow consumer */
+  streamBufferSize?: number;
+  /** Only 0 is supported; Apple's model has no repetition penalties */
+  frequencyPenalty?: number;
+  /** Only 0 is supported; Apple's model has no repetition penalties */
+  presencePenalty?: number;
+  /** Strip null bytes from the prompt instead of rejecting it */
+  sanitize?: boolean;
+  /** "json" requests a JSON object and rejects if the response doesn't parse */
+  responseFormat?: "text" | "json";
+  /** Retries (with backoff) when the model is rate limited or busy */
+  maxRetries?: number;
+  /** Only false is supported; Apple's model doesn't expose log probabilities */
+  includeLogprobs?: boolean;
+  /** Only 0 is supported; Apple's model doesn't expose log probabilities */
+  topLogprobs?: number;
+  /** Reject over-long prompts before generating; defaults to true */
+  checkContextWindow?: boolean;
+  /** Tags (e.g. a request id) echoed on log events and stream chunks */
+  metadata?: Record<string, string>;
+  /** Cut the output after this many characters (possibly mid-sentence) */
+  maxChars?: number;
+  /** Streams only: first deliver a `prompt` event echoing the final prompt */
+  echoPrompt?: boolean;
+  /** Streams only: add a `timings` breakdown to the final event */
+  streamTimings?: boolean;
+  /** BCP-47 tag of the language to reply in, e.g. `fr`; must be supported by the model */
+  language?: string;
+  /** Cancels the generation (honoured by `generateResponse`, `streamResponse` and `generateBatch`) */
+  signal?: AbortSignal;
 }
 
+/** Native trailing options argument shared by the generation functions */
+function nativeOptions(options: GenerationOptions) {
+  return {
+    topP: options.topP,
+    seed: options.seed,
+    stopSequences: options.stopSequences,
+    timeoutMs: options.timeoutMs,
+    idleTimeoutMs: options.idleTimeoutMs,
+    streamBufferSize: options.streamBufferSize,
+    frequencyPenalty: options.frequencyPenalty,
+    presencePenalty: options.presencePenalty,
+    sanitize: options.sanitize,
+    responseFormat: options.responseFormat,
+    maxRetries: options.maxRetries,
+    includeLogprobs: options.includeLogprobs,
+    topLogprobs: options.topLogprobs,
+    checkContextWindow: options.checkContextWindow,
+    metadata: options.metadata,
+    maxChars: options.maxChars,
+    echoPrompt: options.echoPrompt,
+    streamTimings: options.streamTimings,
+    language: options.language,
+  };
+}
+
+export type AvailabilityReason =
+  | "Available"
+  | "DeviceNotEligible"
+  | "AppleIntelligenceNotEnabled"
+  | "ModelNotReady"
+  | "UnsupportedPlatform"
+  | "Unknown";
+
 export interface ModelAvailability {
   available: boolean;
   reason: string;
+  reasonCode: AvailabilityReason;
+}
+
+/** Outcome of one `generateBatch` prompt: `text`, or `error` and `code` */
+export interface BatchResult {
+  text?: string | null;
+  error?: string | null;
+  code?: string | null;
+  /** The signal aborted before or while this prompt ran */
+  cancelled: boolean;
 }
 
 // OpenAI-compatible response types
+/** Event passed to native stream callbacks; the last one has `done` set */
+interface StreamChunk {
+  /** Newly generated text */
+  delta: string;
+  /** Everything generated, on the last event only; join the deltas otherwise */
+  text: string;
+  /** Estimated tokens generated so far */
+  tokensSoFar: number;
+  done: boolean;
+  /** Final event after `cancelStream` */
+  cancelled: boolean;
+  error?: string | null;
+  code?: string | null;
+}
+
 export interface ChatCompletionChunk {
   id: string;
   object: "chat.completion.chunk";
@@ -64,28 +164,60 @@ export class AppleAISDK {
     return native.getSupportedLanguages();
   }
 
-  /** Generate a response for a prompt */
-  async generateResponse(
+  /** Generate a response for a prompt; `requestId` matches the call's log events */
+  generateResponse(
     prompt: string,
     options: GenerationOptions = {}
-  ): Promise<string> {
+  ): Promise<string> & { requestId: number } {
     return native.generateResponse(
       prompt,
       options.temperature ?? undefined,
-      options.maxTokens ?? undefined
+      options.maxTokens ?? undefined,
+      options.signal ?? undefined,
+      nativeOptions(options)
+    );
+  }
+
+  /** Generate a response under persistent system instructions */
+  generateResponseWithInstructions(
+    instructions: string,
+    prompt: string,
+    options: GenerationOptions = {}
+  ): Promise<string> & { requestId: number } {
+    return native.generateResponseWithInstructions(
+      instructions,
+      prompt,
+      options.temperature ?? undefined,
+      options.maxTokens ?? undefined,
+      nativeOptions(options)
+    );
+  }
+
+  /** Generate a response for each prompt; failures are reported per item */
+  async generateBatch(
+    prompts: string[],
+    options: GenerationOptions = {}
+  ): Promise<BatchResult[]> {
+    return native.generateBatch(
+      prompts,
+      options.temperature ?? undefined,
+      options.maxTokens ?? undefined,
+      nativeOptions(options),
+      options.signal
     );
   }
 
   /** Generate a response using conversation history */
-  async generateResponseWithHistory(
+  generateResponseWithHistory(
     messages: ChatMessage[],
     options: GenerationOptions = {}
-  ): Promise<string> {
+  ): Promise<string> & { requestId: number } {
     const messagesJson = JSON.stringify(messages);
     return native.generateResponseWithHistory(
       messagesJson,
       options.temperature ?? undefined,
-      options.maxTokens ?? undefined
+      options.maxTokens ?? undefined,
+      nativeOptions(options)
     );
   }
 
@@ -111,24 +243,33 @@ export class AppleAISDK {
 
     let error: any = null;
 
+    const push = (chatChunk: ChatCompletionChunk) => {
+      // If the consumer is waiting, resolve immediately; otherwise buffer
+      if (pendingResolve) {
+        pendingResolve({ value: chatChunk, done: false });
+        pendingResolve = null;
+        pendingReject = null;
+      } else {
+        queue.push(chatChunk);
+      }
+    };
+
     // Push-based native callback
-    const handleChunk = (err: any, chunk?: string | null) => {
-      if (err) {
-        error = err;
+    const handleChunk = (chunk: StreamChunk) => {
+      if (chunk.error != null) {
+        error = Object.assign(new Error(chunk.error), { code: chunk.code });
         done = true;
         if (pendingReject) {
-          pendingReject(err);
+          pendingReject(error);
           pendingResolve = null;
           pendingReject = null;
         }
         return;
       }
 
-      let chatChunk: ChatCompletionChunk;
-
-      if (chunk == null || chunk === "") {
-        // Final chunk
-        chatChunk = {
+      if (chunk.delta) {
+        // Content chunk
+        push({
           id: completionId,
           object: "chat.completion.chunk",
           created,
@@ -136,15 +277,21 @@ export class AppleAISDK {
           choices: [
             {
               index: 0,
-              delta: {},
-              finish_reason: "stop",
+              delta: {
+                ...(isFirstChunk ? { role: "assistant" as const } : {}),
+                content: chunk.delta,
+              },
+              finish_reason: null,
             },
           ],
-        };
+        });
+        isFirstChunk = false;
+      }
+
+      if (chunk.done) {
+        // Final chunk
         done = true;
-      } else {
-        // Content chunk
-        chatChunk = {
+        push({
           id: completionId,
           object: "chat.completion.chunk",
           created,
@@ -152,45 +299,35 @@ export class AppleAISDK {
           choices: [
             {
               index: 0,
-              delta: {
-                ...(isFirstChunk ? { role: "assistant" as const } : {}),
-                content: chunk,
-              },
-              finish_reason: null,
+              delta: {},
+              finish_reason: "stop",
             },
           ],
-        };
-        isFirstChunk = false;
-      }
-
-      // If the consumer is waiting, resolve immediately; otherwise buffer
-      if (pendingResolve) {
-        pendingResolve({ value: chatChunk, done: false });
-        pendingResolve = null;
-        pendingReject = null;
-      } else {
-        queue.push(chatChunk);
+        });
       }
     };
 
     // Use the existing streaming mechanism but with messages
     const messagesJson = JSON.stringify(messages);
-    native.generateResponseStreamWithHistory?.(
-      messagesJson,
-      options.temperature ?? undefined,
-      options.maxTokens ?? undefined,
-      handleChunk
-    ) ??
+    const streamId: number =
+      native.generateResponseWithHistoryStream?.(
+        messagesJson,
+        options.temperature ?? undefined,
+        options.maxTokens ?? undefined,
+        handleChunk,
+        nativeOptions(options)
+      ) ??
       (() => {
         // Fallback: if streaming with history isn't available, convert the prompt and use regular streaming
         const prompt =
           messages.map((m) => `${m.role}: ${m.content}`).join("\n") +
           "\nassistant:";
-        native.generateResponseStream(
+        return native.generateResponseStream(
           prompt,
           options.temperature ?? undefined,
           options.maxTokens ?? undefined,
-          handleChunk
+          handleChunk,
+          nativeOptions(options)
         );
       })();
 
@@ -215,6 +352,7 @@ export class AppleAISDK {
         );
       },
       async return(): Promise<IteratorResult<ChatCompletionChunk>> {
+        if (!done) native.cancelStream(streamId);
         done = true;
         return { value: undefined, done: true };
       },
@@ -310,42 +448,45 @@ export class AppleAISDK {
     let error: any = null;
 
     // Push-based native callback
-    const handleChunk = (err: any, chunk?: string | null) => {
-      if (err) {
-        error = err;
+    const handleChunk = (chunk: StreamChunk) => {
+      if (chunk.error != null) {
+        error = Object.assign(new Error(chunk.error), { code: chunk.code });
         done = true;
         if (pendingReject) {
-          pendingReject(err);
+          pendingReject(error);
           pendingResolve = null;
           pendingReject = null;
         }
         return;
       }
 
-      if (chunk == null || chunk === "") {
-        done = true;
+      if (chunk.delta) {
+        // If the consumer is waiting, resolve immediately; otherwise buffer
         if (pendingResolve) {
-          pendingResolve({ value: undefined, done: true });
+          pendingResolve({ value: chunk.delta, done: false });
           pendingResolve = null;
+          pendingReject = null;
+        } else {
+          queue.push(chunk.delta);
         }
-        return;
       }
 
-      // If the consumer is waiting, resolve immediately; otherwise buffer
-      if (pendingResolve) {
-        pendingResolve({ value: chunk, done: false });
-        pendingResolve = null;
-        pendingReject = null;
-      } else {
-        queue.push(chunk);
+      if (chunk.done) {
+        done = true;
+        if (pendingResolve) {
+          pendingResolve({ value: undefined, done: true });
+          pendingResolve = null;
+        }
       }
     };
 
-    native.generateResponseStream(
+    const streamId: number = native.generateResponseStream(
       prompt,
       options.temperature ?? undefined,
       options.maxTokens ?? undefined,
-      handleChunk
+      handleChunk,
+      nativeOptions(options),
+      options.signal ?? undefined
     );
 
     return {
@@ -367,6 +508,7 @@ export class AppleAISDK {
         });
       },
       async return(): Promise<IteratorResult<string>> {
+        if (!done) native.cancelStream(streamId);
         done = true;
         return { value: undefined, done: true };
       },
@@ -380,6 +522,35 @@ export class AppleAISDK {
     };
   }
 
+  /**
+   * Like `streamResponse`, but pulls chunks from the native side so
+   * generation waits while the consumer falls behind
+   */
+  async *streamResponseIter(
+    prompt: string,
+    options: GenerationOptions = {}
+  ): AsyncGenerator<string, void, undefined> {
+    const reader = native.generateResponseStreamIter(
+      prompt,
+      options.temperature ?? undefined,
+      options.maxTokens ?? undefined,
+      nativeOptions(options)
+    );
+    try {
+      while (true) {
+        const chunk: StreamChunk | null = await reader.nextChunk();
+        if (!chunk) return;
+        if (chunk.error) {
+          throw Object.assign(new Error(chunk.error), { code: chunk.code });
+        }
+        if (chunk.delta) yield chunk.delta;
+        if (chunk.done) return;
+      }
+    } finally {
+      reader.cancel();
+    }
+  }
+
   /** Generate a structured object based on a Zod/JSON schema */
   async generateStructured<T = any>(params: {
     prompt: string;